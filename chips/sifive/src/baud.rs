@@ -0,0 +1,101 @@
+//! Baud rate divisor calculations.
+//!
+//! SiFive peripherals which derive their bit timing from the core clock do so
+//! through a divisor register, where
+//!
+//! ```text
+//!              f_clk
+//! f_baud = -------------
+//!           divisor + 1
+//! ```
+//!
+//! These helpers compute divisors for a requested rate and report how close
+//! the rate that is actually achieved comes to the requested one.
+
+/// Compute the divisor which gets closest to `baud` from a clock of `clk` Hz.
+///
+/// The divisor is rounded to the nearest integer. Returns `None` if `baud` is
+/// zero, faster than the clock, or so slow that the divisor does not fit in
+/// 16 bits.
+pub const fn divisor(clk: u32, baud: u32) -> Option<u16> {
+    // Rounding would turn rates of up to twice the clock into a divisor of
+    // 0, which runs at the clock rate and is off by up to 50%.
+    if baud == 0 || baud > clk {
+        return None;
+    }
+
+    // Round to nearest, using 64-bit math so `clk + baud / 2` can't overflow.
+    let ratio = (clk as u64 + baud as u64 / 2) / baud as u64;
+    if ratio == 0 || ratio > u16::MAX as u64 + 1 {
+        None
+    } else {
+        Some((ratio - 1) as u16)
+    }
+}
+
+/// The baud rate that results from programming `divisor` with a clock of
+/// `clk` Hz.
+pub const fn achieved_baud(clk: u32, divisor: u16) -> u32 {
+    clk / (divisor as u32 + 1)
+}
+
+/// The error of the best achievable rate relative to `baud`, in parts per
+/// million.
+///
+/// A positive value means the line runs faster than requested. Returns
+/// `i32::MAX` if `baud` can't be reached at all (see [`divisor`]).
+pub const fn error_ppm(clk: u32, baud: u32) -> i32 {
+    match divisor(clk, baud) {
+        Some(divisor) => {
+            let achieved = achieved_baud(clk, divisor) as i64;
+            ((achieved - baud as i64) * 1_000_000 / baud as i64) as i32
+        }
+        None => i32::MAX,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{achieved_baud, divisor, error_ppm};
+
+    #[test]
+    fn divisor_rounds_to_nearest() {
+        // 16 MHz / 115200 = 138.9, so 139 - 1.
+        assert_eq!(divisor(16_000_000, 115200), Some(138));
+        // 32 MHz / 115200 = 277.8, so 278 - 1.
+        assert_eq!(divisor(32_000_000, 115200), Some(277));
+        // Exact division.
+        assert_eq!(divisor(16_000_000, 1_000_000), Some(15));
+        assert_eq!(divisor(16_000_000, 16_000_000), Some(0));
+    }
+
+    #[test]
+    fn divisor_range() {
+        assert_eq!(divisor(16_000_000, 0), None);
+        // Faster than the clock.
+        assert_eq!(divisor(16_000_000, 40_000_000), None);
+        assert_eq!(divisor(16_000_000, 16_000_001), None);
+        assert_eq!(divisor(16_000_000, 31_999_999), None);
+        // Slowest rate which still fits in 16 bits.
+        assert_eq!(divisor(65_536, 1), Some(u16::MAX));
+        assert_eq!(divisor(65_537, 1), None);
+    }
+
+    #[test]
+    fn achieved() {
+        assert_eq!(achieved_baud(16_000_000, 138), 115107);
+        assert_eq!(achieved_baud(16_000_000, 0), 16_000_000);
+        assert_eq!(achieved_baud(65_536, u16::MAX), 1);
+    }
+
+    #[test]
+    fn error() {
+        assert_eq!(error_ppm(16_000_000, 1_000_000), 0);
+        // 115107 vs 115200.
+        assert_eq!(error_ppm(16_000_000, 115200), -807);
+        // 16 MHz / 9 = 1777777 vs 1.8 Mbaud.
+        assert_eq!(error_ppm(16_000_000, 1_800_000), -12346);
+        assert_eq!(error_ppm(16_000_000, 0), i32::MAX);
+        assert_eq!(error_ppm(16_000_000, 16_000_001), i32::MAX);
+    }
+}
@@ -4,6 +4,7 @@
 #![crate_name = "sifive"]
 #![crate_type = "rlib"]
 
+pub mod baud;
 pub mod clint;
 pub mod gpio;
 pub mod prci;
@@ -1,16 +1,50 @@
 //! UART driver.
+//!
+//! Cargo features
+//! --------------
+//!
+//! - `async`: `Uart::register_tx_waker`.
+//! - `std`: the `host` module, an in-memory backend for testing code built
+//!   on the UART HIL on the host.
+//! - `diagnostics`: runtime statistics for debugging. Without this feature
+//!   the following APIs do not exist, and the driver only keeps the state it
+//!   needs to transmit and receive:
+//!   - `Uart::tx_byte_count`
+//!   - `Uart::rx_byte_count`
+//!   - `Uart::spurious_interrupt_count`
+//!   - `Uart::peak_rx_backlog`
+//!   - `Uart::reset_diagnostics`
+//!   - `Uart::config_history`, together with `ConfigEvent` and
+//!     `ConfigChange`
+//!   - `Uart::attach_rx_tap`, `Uart::detach_rx_tap` and
+//!     `Uart::rx_tap_written`
+//!   - `Uart::mark_wakeup` and `Uart::wakeup_count`
+//!   - `Uart::completed_transmits`, `Uart::completed_receives`,
+//!     `Uart::reset_completed_transmits` and `Uart::reset_completed_receives`
+//!   - `Uart::debug_state`, together with `UartDebugState`
 
 use core::cell::Cell;
+use core::fmt;
 use kernel::ErrorCode;
 
+use crate::baud;
 use crate::gpio;
+use kernel::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
 use kernel::hil;
+use kernel::hil::time::{Frequency, Ticks64};
+#[cfg(feature = "async")]
+use kernel::utilities::cells::MapCell;
 use kernel::utilities::cells::OptionalCell;
 use kernel::utilities::cells::TakeCell;
 use kernel::utilities::registers::interfaces::{ReadWriteable, Readable, Writeable};
 use kernel::utilities::registers::{register_bitfields, ReadOnly, ReadWrite};
 use kernel::utilities::StaticRef;
 
+#[cfg(any(test, feature = "std"))]
+pub mod host;
+
 #[repr(C)]
 pub struct UartRegisters {
     /// Transmit Data Register
@@ -29,6 +63,10 @@ pub struct UartRegisters {
     div: ReadWrite<u32, div::Register>,
 }
 
+/// Number of bytes of MMIO space occupied by the UART registers, e.g. for
+/// setting up MPU regions or address-range checks.
+pub const UART_REGISTERS_SIZE: usize = core::mem::size_of::<UartRegisters>();
+
 register_bitfields![u32,
     txdata [
         full OFFSET(31) NUMBITS(1) [],
@@ -59,15 +97,544 @@ register_bitfields![u32,
     ]
 ];
 
+/// Longest sync preamble accepted by [`Uart::receive_after_prefix`].
+pub const MAX_RX_PREFIX_LEN: usize = 8;
+
+/// Longest delimiter accepted by [`Uart::receive_until_seq`].
+pub const MAX_RX_DELIMITER_LEN: usize = 8;
+
+/// Number of entries in the hardware TX and RX FIFOs. This is what the
+/// driver assumes until `Uart::detect_fifo_depth` finds otherwise, and the
+/// most it supports, as the 3-bit watermarks can't express deeper FIFOs.
+const FIFO_DEPTH: usize = 8;
+
+/// Most bytes `Uart::detect_fifo_depth` writes while looking for the end of
+/// the TX FIFO.
+const MAX_FIFO_DEPTH_PROBE: usize = 64;
+
+/// Upper bound on how often `Uart::handle_interrupt` re-reads `ip`.
+const MAX_INTERRUPT_PASSES: usize = 4;
+
+/// Depth of the token bucket of `Uart::set_tx_rate_limit`, in bytes.
+pub const TX_RATE_LIMIT_BURST: u32 = FIFO_DEPTH as u32;
+
+/// Clock and one-shot alarm used by the timing features of the UART.
+///
+/// The driver does not depend on a particular timer: a board hands it any
+/// `TimeSource` with `Uart::set_time_source` and, because the alarm calls
+/// back into the driver, also registers the UART as the alarm's
+/// `hil::time::AlarmClient`. Every `hil::time::Alarm` with 64-bit ticks, such
+/// as the CLINT or a virtual alarm on top of it, implements this trait.
+///
+/// Without a time source, features which wait for a deadline return
+/// `NOSUPPORT` (or, for `transmit_sync`, fall back to polling).
+pub trait TimeSource {
+    /// The current time in ticks.
+    fn now(&self) -> u64;
+
+    /// The tick rate in Hz.
+    fn frequency(&self) -> u32;
+
+    /// Whole ticks per microsecond. This is 0 for sources running below
+    /// 1 MHz, such as the 32 kHz CLINT; use `us_to_ticks` for conversions.
+    fn ticks_per_us(&self) -> u32 {
+        self.frequency() / 1_000_000
+    }
+
+    /// Convert microseconds to ticks, rounding up so a wait is never shorter
+    /// than requested.
+    fn us_to_ticks(&self, us: u32) -> u64 {
+        (us as u64 * self.frequency() as u64 + 999_999) / 1_000_000
+    }
+
+    /// Convert ticks to microseconds, rounding down.
+    fn ticks_to_us(&self, ticks: u64) -> u64 {
+        ticks * 1_000_000 / self.frequency() as u64
+    }
+
+    /// Fire the alarm `dt` ticks from now, replacing any earlier deadline.
+    fn arm(&self, dt: u64);
+}
+
+impl<'a, A: hil::time::Alarm<'a, Ticks = Ticks64>> TimeSource for A {
+    fn now(&self) -> u64 {
+        hil::time::Time::now(self).into_u64()
+    }
+
+    fn frequency(&self) -> u32 {
+        A::Frequency::frequency()
+    }
+
+    fn arm(&self, dt: u64) {
+        self.set_alarm(hil::time::Time::now(self), Ticks64::from(dt));
+    }
+}
+
+/// Reports the frequency the UART is actually clocked with, see
+/// `Uart::verify_clock`.
+///
+/// This is typically implemented by the board on top of the PRCI, from the
+/// oscillator it selected and the PLL and divider settings it programmed.
+pub trait ClockSource {
+    /// The frequency of the bus clock feeding the UART in Hz, or `None` if
+    /// it can't be determined.
+    fn uart_clock_frequency(&self) -> Option<u32>;
+}
+
+/// Largest baud rate error `Uart::verify_clock` accepts, in parts per
+/// million. UART framing generally tolerates a few percent.
+pub const MAX_CLOCK_BAUD_ERROR_PPM: u32 = 20_000;
+
+/// How often the divisor is written before giving up when
+/// `Uart::set_write_verify` is enabled.
+pub const MAX_DIV_WRITE_ATTEMPTS: usize = 3;
+
+/// Level of the RX pin as seen by `Uart::rx_line_state`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RxLineState {
+    /// The line stayed high: a peer is connected and idle.
+    IdleHigh,
+    /// The line stayed low: a short to ground, a peer holding a break, or a
+    /// peer which is powered off and pulls the line down.
+    StuckLow,
+    /// The line changed level while it was watched: most likely floating,
+    /// or there was traffic.
+    Noisy,
+}
+
+/// Number of times `Uart::rx_line_state` samples the RX pin.
+const RX_LINE_SAMPLES: u32 = 32;
+
+/// Default length of the break sent by `Uart::send_reset_break`, in bit
+/// times. 13 bits is the minimum break LIN requires, and longer than any
+/// frame, so no receiver can mistake it for data.
+pub const DEFAULT_RESET_BREAK_BITS: u32 = 13;
+
+/// The baud rates a packed descriptor can select, see `pack_config`.
+pub const PACKED_BAUD_RATES: [u32; 12] = [
+    1200, 2400, 4800, 9600, 19200, 38400, 57600, 115200, 230400, 460800, 921600, 1_000_000,
+];
+
+const PACKED_BAUD_MASK: u32 = 0xf;
+const PACKED_TWO_STOP_BITS: u32 = 1 << 4;
+
+/// Build a descriptor for `Uart::configure_packed`, so a board can keep
+/// its UART configurations as compact constants.
+///
+/// The descriptor is laid out as:
+/// - bits 0 to 3: the index of `baud_rate` in `PACKED_BAUD_RATES`.
+/// - bit 4: the stop bits, 0 for one and 1 for two.
+/// - bits 5 to 31: reserved, 0.
+///
+/// The other parameters are fixed to what the UART supports: 8 data bits,
+/// no parity and no flow control. The FIFO watermarks are managed by the
+/// driver for each transfer, so they have no field. Panics if `baud_rate`
+/// is not in `PACKED_BAUD_RATES`, which fails the build when evaluated in a
+/// constant.
+pub const fn pack_config(baud_rate: u32, stop_bits: hil::uart::StopBits) -> u32 {
+    let mut index = 0;
+    while PACKED_BAUD_RATES[index] != baud_rate {
+        index += 1;
+        if index == PACKED_BAUD_RATES.len() {
+            panic!("baud rate can't be packed");
+        }
+    }
+    let stop_bits = match stop_bits {
+        hil::uart::StopBits::One => 0,
+        hil::uart::StopBits::Two => PACKED_TWO_STOP_BITS,
+    };
+    index as u32 | stop_bits
+}
+
+/// How `transmit_sync` waits for room in the TX FIFO.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TransmitSyncStrategy {
+    /// Poll `txdata` back-to-back. This has the lowest latency and is the
+    /// default.
+    Spin,
+    /// Execute `nops` `nop` instructions between two polls of `txdata`, which
+    /// reduces the MMIO traffic when other harts share the bus.
+    Backoff { nops: u32 },
+    /// When the FIFO is full, wait (using the time source) for the time it
+    /// takes to drain the whole FIFO at the configured baud rate before
+    /// polling again. Falls back to `Spin` if no `TimeSource` was provided.
+    DrainDelay,
+}
+
+/// How a transmit started with `Uart::transmit_buffer_mode` makes progress.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TransferMode {
+    /// Refill the FIFO from the UART interrupt. This is what
+    /// `transmit_buffer` does.
+    Interrupt,
+    /// Refill the FIFO from a deferred call which keeps re-arming itself
+    /// until the transmit is done, without enabling the UART interrupt.
+    Polled,
+}
+
+/// What happens to bytes which arrive while no receive is posted, see
+/// `Uart::set_no_buffer_policy`.
+///
+/// This only matters once the receiver has been enabled by a receive, and
+/// not while the RX FIFO is used otherwise, e.g. for loopback verification
+/// or by `forward_rx_to`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RxNoBufferPolicy {
+    /// Keep the RX interrupt enabled and discard the bytes. Bytes beyond
+    /// the end of a receive are lost unless the client posts the next
+    /// buffer from its callback, but the next receive only sees data which
+    /// arrived after it was posted. Costs an interrupt per burst of
+    /// unwanted data and no RAM. This is the default.
+    Drop,
+    /// Leave the bytes in the RX FIFO for the next receive. Up to
+    /// `FIFO_DEPTH` bytes are kept; the hardware drops everything after
+    /// that without a trace. If the FIFO is full when the next receive is
+    /// posted, bytes may have been lost, and that receive is reported with
+    /// `hil::uart::Error::OverrunError` (its data is still delivered).
+    Overrun,
+    /// Keep draining the RX FIFO into the ring buffer set with
+    /// `Uart::set_rx_ring`, and hand those bytes to the next receive before
+    /// any newer ones, for continuous reception. Bytes which arrive while
+    /// the ring is full are dropped. Only boards which use this policy
+    /// need to set aside RAM for the ring. Needs the ring and the deferred
+    /// call set with `set_deferred_call`.
+    BufferInRing,
+}
+
+/// Client for transmits started with `Uart::transmit_const`.
+pub trait TransmitConstClient {
+    /// A call to `transmit_const` completed. As the data is immutable and
+    /// stays wherever it lives, no buffer is handed back; `tx_len` is the
+    /// number of bytes transmitted.
+    fn transmitted_const(&self, tx_len: usize, rval: Result<(), ErrorCode>);
+}
+
+/// Supplier of the chunks of a stream started with `Uart::transmit_stream`.
+pub trait ChunkSource {
+    /// The next chunk to transmit, or `None` once the stream is exhausted.
+    /// Called when the stream starts and whenever a chunk has been
+    /// transmitted completely. Empty chunks are handed back without
+    /// transmitting anything, but never from within `transmit_stream`.
+    fn next_chunk(&self) -> Option<&'static mut [u8]>;
+
+    /// A chunk returned by `next_chunk` is done and handed back, e.g. to be
+    /// refilled.
+    fn chunk_transmitted(&self, chunk: &'static mut [u8]);
+
+    /// The stream has ended. `tx_len` is the total number of bytes
+    /// transmitted across all chunks; `rval` is `Ok(())` if `next_chunk`
+    /// returned `None`, or the error which stopped the stream.
+    fn stream_complete(&self, tx_len: usize, rval: Result<(), ErrorCode>);
+}
+
+/// Client for request/response exchanges started with
+/// `Uart::transmit_then_receive`.
+pub trait TransactionClient {
+    /// The exchange has ended, successfully or not; both buffers are handed
+    /// back.
+    ///
+    /// `rval` and `error` are those of the transmit if it failed, in which
+    /// case no receive was posted and `rx_len` is 0. Otherwise they are those
+    /// of the receive, with `rx_len` bytes of response in `rx_buffer`; a
+    /// response which did not complete within the timeout is reported with
+    /// `Err(ErrorCode::CANCEL)` and `hil::uart::Error::Aborted`, or
+    /// `hil::uart::Error::Truncated` (see `Uart::set_reject_truncated`).
+    fn transaction_done(
+        &self,
+        tx_buffer: &'static mut [u8],
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+        rval: Result<(), ErrorCode>,
+        error: hil::uart::Error,
+    );
+}
+
+/// Progress of an exchange started with `Uart::transmit_then_receive` or
+/// `Uart::transmit_then_await_ack`.
+#[derive(Copy, Clone, PartialEq)]
+enum Transaction {
+    Idle,
+    Transmitting,
+    Receiving,
+    /// The data of a `transmit_then_await_ack` is being sent.
+    AckTransmitting,
+    /// The data has been sent, watching RX for the acknowledgment.
+    AwaitingAck,
+}
+
+/// Client notified when loopback verification of a transmit, see
+/// `Uart::enable_tx_verify`, finds a wrong echo.
+pub trait TxVerifyClient {
+    /// Byte `index` of the transmit was sent as `sent` but echoed back as
+    /// `received`.
+    fn on_tx_verify_error(&self, index: usize, sent: u8, received: u8);
+}
+
+/// Client notified while a long transmit is in progress, see
+/// `Uart::set_tx_chunk`.
+pub trait TransmitProgressClient {
+    /// Another chunk of the active transmit is on the wire: `tx_sent` of
+    /// `tx_len` bytes have been transmitted. The driver keeps the buffer
+    /// until the transmit completes.
+    fn transmit_progress(&self, tx_sent: usize, tx_len: usize);
+}
+
+/// Number of entries kept by `Uart::config_history`.
+#[cfg(feature = "diagnostics")]
+pub const CONFIG_HISTORY_LEN: usize = 8;
+
+/// A change to the UART configuration, see `Uart::config_history`.
+#[cfg(feature = "diagnostics")]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ConfigChange {
+    /// `configure` was called successfully with these parameters.
+    Configure {
+        baud_rate: u32,
+        stop_bits: hil::uart::StopBits,
+    },
+    /// The baud rate divisor register was written with this value.
+    Divisor(u16),
+}
+
+/// An entry of the configuration history.
+#[cfg(feature = "diagnostics")]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ConfigEvent {
+    /// The UART which changed, as named at the time of the change.
+    pub name: UartName,
+    pub change: ConfigChange,
+    /// When the change happened, in ticks of the `TimeSource`, or `None` if
+    /// the UART has no time source.
+    pub timestamp: Option<u64>,
+}
+
+/// Counters kept with the `diagnostics` feature.
+#[cfg(feature = "diagnostics")]
+struct Diagnostics {
+    tx_bytes: Cell<u32>,
+    rx_bytes: Cell<u32>,
+    spurious_interrupts: Cell<u32>,
+    peak_rx_backlog: Cell<usize>,
+    config_history: Cell<[Option<ConfigEvent>; CONFIG_HISTORY_LEN]>,
+    config_history_next: Cell<usize>,
+    rx_tap: TakeCell<'static, [u8]>,
+    rx_tap_written: Cell<usize>,
+    woken: Cell<bool>,
+    wakeups: Cell<u32>,
+    completed_transmits: Cell<u32>,
+    completed_receives: Cell<u32>,
+}
+
+#[cfg(feature = "diagnostics")]
+impl Diagnostics {
+    fn new() -> Diagnostics {
+        Diagnostics {
+            tx_bytes: Cell::new(0),
+            rx_bytes: Cell::new(0),
+            spurious_interrupts: Cell::new(0),
+            peak_rx_backlog: Cell::new(0),
+            config_history: Cell::new([None; CONFIG_HISTORY_LEN]),
+            config_history_next: Cell::new(0),
+            rx_tap: TakeCell::empty(),
+            rx_tap_written: Cell::new(0),
+            woken: Cell::new(false),
+            wakeups: Cell::new(0),
+            completed_transmits: Cell::new(0),
+            completed_receives: Cell::new(0),
+        }
+    }
+}
+
+/// Human-readable identification of a UART, see `Uart::name`.
+///
+/// Displays as the name set with `Uart::set_name`, or as the base address of
+/// the registers (e.g. `uart@0x10013000`) if no name was set.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct UartName {
+    name: Option<&'static str>,
+    base: usize,
+}
+
+impl fmt::Display for UartName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name {
+            Some(name) => f.write_str(name),
+            None => write!(f, "uart@{:#010x}", self.base),
+        }
+    }
+}
+
+/// Snapshot of the driver state for crash analysis, see `Uart::debug_state`.
+///
+/// Displays as a single line, e.g.
+/// `uart0: tx 3/64 rx idle baud 115200 nstop 1 ie 0x1 ip 0x1`.
+#[cfg(feature = "diagnostics")]
+#[derive(Copy, Clone)]
+pub struct UartDebugState {
+    pub name: UartName,
+    /// Whether a transmit is in progress, of a buffer or otherwise.
+    pub tx_in_progress: bool,
+    /// Whether the driver holds a transmit buffer.
+    pub tx_buffer_held: bool,
+    /// Bytes of the active transmit written to the FIFO so far.
+    pub tx_index: usize,
+    /// Length of the active (or last) transmit.
+    pub tx_len: usize,
+    /// Whether the driver holds a receive buffer.
+    pub rx_buffer_held: bool,
+    /// Bytes of the active receive stored so far.
+    pub rx_index: usize,
+    /// Length of the active (or last) receive.
+    pub rx_len: usize,
+    /// Configured baud rate, 0 before the first `configure`.
+    pub baud_rate: u32,
+    pub stop_bits: hil::uart::StopBits,
+    /// Raw value of the `ie` register.
+    pub ie: u32,
+    /// Raw value of the `ip` register.
+    pub ip: u32,
+}
+
+#[cfg(feature = "diagnostics")]
+impl fmt::Display for UartDebugState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: tx ", self.name)?;
+        if self.tx_in_progress {
+            write!(f, "{}/{}", self.tx_index, self.tx_len)?;
+        } else {
+            f.write_str("idle")?;
+        }
+        f.write_str(" rx ")?;
+        if self.rx_buffer_held {
+            write!(f, "{}/{}", self.rx_index, self.rx_len)?;
+        } else {
+            f.write_str("idle")?;
+        }
+        let stop_bits = match self.stop_bits {
+            hil::uart::StopBits::One => 1,
+            hil::uart::StopBits::Two => 2,
+        };
+        write!(
+            f,
+            " baud {} nstop {} ie {:#x} ip {:#x}",
+            self.baud_rate, stop_bits, self.ie, self.ip
+        )
+    }
+}
+
+/// What the RX path does with bytes drained from the FIFO.
+#[derive(Copy, Clone, PartialEq)]
+enum RxMode {
+    /// Store every byte in the posted buffer.
+    Buffer,
+    /// Discard bytes until the configured prefix has been seen, then switch
+    /// to `Buffer`.
+    AwaitPrefix,
+    /// Store bytes until the line has been idle for 3.5 character times,
+    /// then deliver them as one Modbus RTU frame.
+    RtuFrame,
+    /// Store bytes until the configured delimiter sequence has been
+    /// received.
+    UntilDelimiter,
+}
+
 pub struct Uart<'a> {
     registers: StaticRef<UartRegisters>,
     clock_frequency: u32,
+    name: OptionalCell<&'static str>,
     tx_client: OptionalCell<&'a dyn hil::uart::TransmitClient>,
+    tx_const_client: OptionalCell<&'a dyn TransmitConstClient>,
+    tx_progress_client: OptionalCell<&'a dyn TransmitProgressClient>,
+    tx_verify_client: OptionalCell<&'a dyn TxVerifyClient>,
+    txn_client: OptionalCell<&'a dyn TransactionClient>,
+    txn: Cell<Transaction>,
+    txn_tx_buffer: TakeCell<'static, [u8]>,
+    txn_rx_buffer: TakeCell<'static, [u8]>,
+    txn_rx_len: Cell<usize>,
+    txn_timeout_us: Cell<Option<u32>>,
+    txn_deadline: OptionalCell<u64>,
+    txn_ack: Cell<u8>,
+    txn_nak: Cell<Option<u8>>,
     rx_client: OptionalCell<&'a dyn hil::uart::ReceiveClient>,
+    forward_to: OptionalCell<&'a Uart<'a>>,
+    #[cfg(any(test, feature = "std"))]
+    host: OptionalCell<&'a host::HostStream>,
+    forward_from: OptionalCell<&'a Uart<'a>>,
+    rx_no_buffer_policy: Cell<RxNoBufferPolicy>,
+    rx_ring: TakeCell<'static, [u8]>,
+    rx_ring_start: Cell<usize>,
+    rx_ring_len: Cell<usize>,
+    rx_overrun: Cell<bool>,
     stop_bits: Cell<hil::uart::StopBits>,
+    baud_rate: Cell<u32>,
+    expected_div: OptionalCell<u16>,
+    clock_drift_ppm: Cell<i32>,
+    div_write_verify: Cell<bool>,
+    tx_restore_baud: OptionalCell<u32>,
+    tx_restore_deadline: OptionalCell<u64>,
+    tx_restore_rval: OptionalCell<Result<(), ErrorCode>>,
+    configured: Cell<bool>,
+    reset_break_bits: Cell<u32>,
+    fifo_depth: Cell<usize>,
+    time_source: OptionalCell<&'a dyn TimeSource>,
+    transmit_sync_strategy: Cell<TransmitSyncStrategy>,
     buffer: TakeCell<'static, [u8]>,
+    tx_const: OptionalCell<&'static [u8]>,
+    tx_stream: OptionalCell<&'a dyn ChunkSource>,
+    tx_stream_sent: Cell<usize>,
     len: Cell<usize>,
     index: Cell<usize>,
+    tx_chunk: Cell<usize>,
+    tx_chunk_end: Cell<usize>,
+    tx_fifo_bytes: Cell<usize>,
+    tx_null: Cell<bool>,
+    tx_deferred_complete: Cell<bool>,
+    deferred_callbacks: Cell<bool>,
+    tx_deferred_rval: OptionalCell<Result<(), ErrorCode>>,
+    tx_polled: Cell<bool>,
+    tx_strobed: Cell<bool>,
+    tx_verify: Cell<bool>,
+    tx_verify_active: Cell<bool>,
+    tx_verify_pending: OptionalCell<(usize, u8)>,
+    tx_frame_delimiter: Cell<Option<u8>>,
+    rx_sync_timeout_us: Cell<Option<u32>>,
+    rx_capture: TakeCell<'static, [(u32, u8)]>,
+    rx_capture_stopped: TakeCell<'static, [(u32, u8)]>,
+    rx_capture_len: Cell<usize>,
+    rx_capture_last: Cell<u64>,
+    rx_gap_measure: Cell<bool>,
+    rx_last_arrival: OptionalCell<u64>,
+    rx_last_gap: OptionalCell<u64>,
+    tx_rate_limit: Cell<u32>,
+    tx_tokens: Cell<u32>,
+    tx_tokens_updated: Cell<u64>,
+    tx_resume_deadline: OptionalCell<u64>,
+    rtu_gap_deadline: OptionalCell<u64>,
+    tx_delimiter_pending: Cell<bool>,
+    deferred_caller: OptionalCell<&'static DynamicDeferredCall>,
+    deferred_handle: OptionalCell<DeferredCallHandle>,
+    #[cfg(feature = "async")]
+    tx_waker: MapCell<core::task::Waker>,
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_deferred_buffer: TakeCell<'static, [u8]>,
+    rx_deferred_status: OptionalCell<(Result<(), ErrorCode>, hil::uart::Error)>,
+    rx_len: Cell<usize>,
+    rx_index: Cell<usize>,
+    rx_mode: Cell<RxMode>,
+    rx_start_mode: Cell<RxMode>,
+    rx_retry_max: Cell<u32>,
+    rx_retries: Cell<u32>,
+    rx_prefix: Cell<[u8; MAX_RX_PREFIX_LEN]>,
+    rx_prefix_len: Cell<usize>,
+    rx_prefix_matched: Cell<usize>,
+    rx_delimiter: Cell<[u8; MAX_RX_DELIMITER_LEN]>,
+    rx_delimiter_len: Cell<usize>,
+    rx_delimiter_matched: Cell<usize>,
+    rx_keep_delimiter: Cell<bool>,
+    rx_reject_truncated: Cell<bool>,
+    rx_overflow: Cell<bool>,
+    #[cfg(feature = "diagnostics")]
+    diagnostics: Diagnostics,
 }
 
 #[derive(Copy, Clone)]
@@ -80,12 +647,100 @@ impl<'a> Uart<'a> {
         Uart {
             registers: base,
             clock_frequency: clock_frequency,
+            name: OptionalCell::empty(),
             tx_client: OptionalCell::empty(),
+            tx_const_client: OptionalCell::empty(),
+            tx_progress_client: OptionalCell::empty(),
+            tx_verify_client: OptionalCell::empty(),
+            txn_client: OptionalCell::empty(),
+            txn: Cell::new(Transaction::Idle),
+            txn_tx_buffer: TakeCell::empty(),
+            txn_rx_buffer: TakeCell::empty(),
+            txn_rx_len: Cell::new(0),
+            txn_timeout_us: Cell::new(None),
+            txn_deadline: OptionalCell::empty(),
+            txn_ack: Cell::new(0),
+            txn_nak: Cell::new(None),
             rx_client: OptionalCell::empty(),
+            forward_to: OptionalCell::empty(),
+            #[cfg(any(test, feature = "std"))]
+            host: OptionalCell::empty(),
+            forward_from: OptionalCell::empty(),
+            rx_no_buffer_policy: Cell::new(RxNoBufferPolicy::Drop),
+            rx_ring: TakeCell::empty(),
+            rx_ring_start: Cell::new(0),
+            rx_ring_len: Cell::new(0),
+            rx_overrun: Cell::new(false),
             stop_bits: Cell::new(hil::uart::StopBits::One),
+            baud_rate: Cell::new(115200),
+            expected_div: OptionalCell::empty(),
+            clock_drift_ppm: Cell::new(0),
+            div_write_verify: Cell::new(false),
+            tx_restore_baud: OptionalCell::empty(),
+            tx_restore_deadline: OptionalCell::empty(),
+            tx_restore_rval: OptionalCell::empty(),
+            configured: Cell::new(false),
+            reset_break_bits: Cell::new(DEFAULT_RESET_BREAK_BITS),
+            fifo_depth: Cell::new(FIFO_DEPTH),
+            time_source: OptionalCell::empty(),
+            transmit_sync_strategy: Cell::new(TransmitSyncStrategy::Spin),
             buffer: TakeCell::empty(),
+            tx_const: OptionalCell::empty(),
+            tx_stream: OptionalCell::empty(),
+            tx_stream_sent: Cell::new(0),
             len: Cell::new(0),
             index: Cell::new(0),
+            tx_chunk: Cell::new(0),
+            tx_chunk_end: Cell::new(0),
+            tx_fifo_bytes: Cell::new(0),
+            tx_null: Cell::new(false),
+            tx_deferred_complete: Cell::new(false),
+            deferred_callbacks: Cell::new(false),
+            tx_deferred_rval: OptionalCell::empty(),
+            tx_polled: Cell::new(false),
+            tx_strobed: Cell::new(false),
+            tx_verify: Cell::new(false),
+            tx_verify_active: Cell::new(false),
+            tx_verify_pending: OptionalCell::empty(),
+            tx_frame_delimiter: Cell::new(None),
+            rx_sync_timeout_us: Cell::new(None),
+            rx_capture: TakeCell::empty(),
+            rx_capture_stopped: TakeCell::empty(),
+            rx_capture_len: Cell::new(0),
+            rx_capture_last: Cell::new(0),
+            rx_gap_measure: Cell::new(false),
+            rx_last_arrival: OptionalCell::empty(),
+            rx_last_gap: OptionalCell::empty(),
+            tx_rate_limit: Cell::new(0),
+            tx_tokens: Cell::new(0),
+            tx_tokens_updated: Cell::new(0),
+            tx_resume_deadline: OptionalCell::empty(),
+            rtu_gap_deadline: OptionalCell::empty(),
+            tx_delimiter_pending: Cell::new(false),
+            deferred_caller: OptionalCell::empty(),
+            deferred_handle: OptionalCell::empty(),
+            #[cfg(feature = "async")]
+            tx_waker: MapCell::empty(),
+            rx_buffer: TakeCell::empty(),
+            rx_deferred_buffer: TakeCell::empty(),
+            rx_deferred_status: OptionalCell::empty(),
+            rx_len: Cell::new(0),
+            rx_index: Cell::new(0),
+            rx_mode: Cell::new(RxMode::Buffer),
+            rx_start_mode: Cell::new(RxMode::Buffer),
+            rx_retry_max: Cell::new(0),
+            rx_retries: Cell::new(0),
+            rx_prefix: Cell::new([0; MAX_RX_PREFIX_LEN]),
+            rx_prefix_len: Cell::new(0),
+            rx_prefix_matched: Cell::new(0),
+            rx_delimiter: Cell::new([0; MAX_RX_DELIMITER_LEN]),
+            rx_delimiter_len: Cell::new(0),
+            rx_delimiter_matched: Cell::new(0),
+            rx_keep_delimiter: Cell::new(true),
+            rx_reject_truncated: Cell::new(false),
+            rx_overflow: Cell::new(false),
+            #[cfg(feature = "diagnostics")]
+            diagnostics: Diagnostics::new(),
         }
     }
 
@@ -95,173 +750,4673 @@ impl<'a> Uart<'a> {
         rx.iof0();
     }
 
-    fn set_baud_rate(&self, baud_rate: u32) {
-        let regs = self.registers;
+    /// Name this UART, e.g. `"UART1"`, to tell it apart from the others on
+    /// the board in debug output.
+    pub fn set_name(&self, name: &'static str) {
+        self.name.set(name);
+    }
 
-        //            f_clk
-        // f_baud = ---------
-        //           div + 1
-        let divisor = (self.clock_frequency / baud_rate) - 1;
+    /// How this UART identifies itself in debug output: the name given with
+    /// `set_name`, or else its base address.
+    pub fn name(&self) -> UartName {
+        UartName {
+            name: self.name.extract(),
+            base: &*self.registers as *const UartRegisters as usize,
+        }
+    }
 
-        regs.div.write(div::div.val(divisor));
+    /// The slowest and fastest baud rates this UART's clock can produce, as
+    /// `(min, max)`. `configure` accepts every rate in this range.
+    ///
+    /// The UART divides its clock by `div + 1` for a 16-bit `div`. The
+    /// fastest rate is the clock itself, with `div = 0`. The slowest is
+    /// clock / 65536 with `div = 65535`, rounded up to a whole rate which
+    /// still rounds to a divisor that fits. Rates above the maximum are
+    /// rejected. The clock is the one `configure` uses, i.e. corrected by
+    /// `adjust_for_drift`.
+    pub fn baud_range(&self) -> (u32, u32) {
+        let clk = self.uart_clock();
+        let min = (clk as u64 + 65535) / 65536;
+        (min as u32, baud::achieved_baud(clk, 0))
     }
 
-    fn enable_tx_interrupt(&self) {
-        let regs = self.registers;
-        regs.ie.modify(interrupt::txwm::SET);
+    /// Look at the level of the RX pin to tell a dead peer from a wiring
+    /// fault when no data arrives.
+    ///
+    /// `rx` must be the pin routed to this UART's RX; it is read through its
+    /// GPIO input register, which works while the pin is assigned to the
+    /// UART. The pin is sampled `RX_LINE_SAMPLES` times, one bit time apart
+    /// at the configured baud rate if a time source is available and back
+    /// to back otherwise. An idle UART line is high, so always-high is
+    /// reported as `IdleHigh`, always-low as `StuckLow` and anything else as
+    /// `Noisy`.
+    ///
+    /// This is a heuristic. Regular traffic also looks `Noisy`, so call it
+    /// while the peer is expected to be quiet. A floating line can sit at
+    /// one level for the whole (short) sampling window, especially without
+    /// a time source, so `IdleHigh` or `StuckLow` is not proof that the
+    /// line is connected; repeated calls make that more likely.
+    pub fn rx_line_state(&self, rx: &gpio::GpioPin) -> RxLineState {
+        let bit_time_us = (1_000_000 + self.baud_rate.get() - 1) / self.baud_rate.get();
+
+        let mut high = 0;
+        for _ in 0..RX_LINE_SAMPLES {
+            if hil::gpio::Input::read(rx) {
+                high += 1;
+            }
+            self.delay_us(bit_time_us);
+        }
+
+        match high {
+            RX_LINE_SAMPLES => RxLineState::IdleHigh,
+            0 => RxLineState::StuckLow,
+            _ => RxLineState::Noisy,
+        }
     }
 
-    fn disable_tx_interrupt(&self) {
-        let regs = self.registers;
-        regs.ie.modify(interrupt::txwm::CLEAR);
+    /// Send a long break to reset the bus, as used by LIN-like and multidrop
+    /// protocols: hold TX low for `set_reset_break_bits` bit times
+    /// (`DEFAULT_RESET_BREAK_BITS` by default), then release it.
+    ///
+    /// The UART can't send a break itself, so `tx`, which must be the pin
+    /// routed to this UART's TX, is taken over as a GPIO output driven low,
+    /// and switched back to the UART with `iof0` afterwards, as done by
+    /// `initialize_gpio_pins`. The duration is the number of bits at the
+    /// configured baud rate, rounded up to a whole microsecond, e.g. 113 us
+    /// for 13 bits at 115200 baud. The driver busy-waits for it with the
+    /// time source, and for one more bit time after the release as the
+    /// break delimiter, so the peer sees the line go high before the next
+    /// start bit. Nothing else runs meanwhile, which at low baud rates can
+    /// be milliseconds.
+    ///
+    /// While the pin is a GPIO, whatever the UART shifts out is lost, so this
+    /// returns `BUSY` if a transmit is in progress. Bytes of a completed
+    /// transmit which are still leaving the FIFO are cut off; wait for
+    /// `time_remaining_us` first. If the pin was not routed to the UART or
+    /// has other GPIO settings, they are not restored. Returns `NOSUPPORT`
+    /// without a time source, as the break would have no defined length.
+    pub fn send_reset_break(&self, tx: &gpio::GpioPin) -> Result<(), ErrorCode> {
+        if self.time_source.is_none() {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+        if self.tx_in_progress() {
+            return Err(ErrorCode::BUSY);
+        }
+
+        let baud_rate = self.baud_rate.get() as u64;
+        let break_us = (self.reset_break_bits.get() as u64 * 1_000_000 + baud_rate - 1) / baud_rate;
+        let bit_time_us = (1_000_000 + baud_rate - 1) / baud_rate;
+
+        // Set the output low before enabling it, so the line goes straight
+        // from idle to the break.
+        hil::gpio::Output::clear(tx);
+        hil::gpio::Configure::make_output(tx);
+        self.delay_us(core::cmp::min(break_us, u32::MAX as u64) as u32);
+
+        tx.iof0();
+        hil::gpio::Configure::disable_output(tx);
+        self.delay_us(bit_time_us as u32);
+
+        Ok(())
     }
 
-    pub fn handle_interrupt(&self) {
+    /// Set the length of the break sent by `send_reset_break`, in bit
+    /// times at the configured baud rate. Protocols generally specify a
+    /// minimum, which this must be at least; values below 11 bits are not
+    /// distinguishable from a frame of zeros.
+    pub fn set_reset_break_bits(&self, bits: u32) {
+        self.reset_break_bits.set(bits);
+    }
+
+    /// Check the clock frequency this UART was created with against the
+    /// clock it actually runs from.
+    ///
+    /// The divisor is computed from the frequency passed to `new`, corrected
+    /// by `adjust_for_drift`, so if the board file assumes a different clock
+    /// than the PRCI was set up for, the baud rate is off. This takes the
+    /// divisor for the current baud rate, works out the rate it really produces at the frequency reported
+    /// by `src`, and returns `INVAL` if that is off by more than
+    /// `MAX_CLOCK_BAUD_ERROR_PPM`. Small differences which still give a
+    /// usable baud rate are accepted. Returns `NOSUPPORT` if `src` can't
+    /// tell the frequency.
+    pub fn verify_clock(&self, src: &dyn ClockSource) -> Result<(), ErrorCode> {
+        let actual = src.uart_clock_frequency().ok_or(ErrorCode::NOSUPPORT)?;
+        let baud_rate = self.baud_rate.get();
+        let divisor = baud::divisor(self.uart_clock(), baud_rate).ok_or(ErrorCode::INVAL)?;
+
+        let achieved = baud::achieved_baud(actual, divisor) as i64;
+        let error_ppm = (achieved - baud_rate as i64).abs() * 1_000_000 / baud_rate as i64;
+        if error_ppm > MAX_CLOCK_BAUD_ERROR_PPM as i64 {
+            Err(ErrorCode::INVAL)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Route the pins and configure and enable the UART, in the order which
+    /// avoids glitches on the line.
+    ///
+    /// 1. `params` are checked first, so nothing is touched if `configure`
+    ///    would reject them.
+    /// 2. The transmitter and receiver are disabled and the configuration is
+    ///    applied, so the divisor is right before anything reaches the pins.
+    /// 3. The pins are switched to the UART.
+    /// 4. The driver waits one frame time (if a time source is available),
+    ///    so the pin mux has settled and the peer sees an idle line before
+    ///    the first start bit, and the receiver doesn't sample the switch
+    ///    as a byte.
+    /// 5. The transmitter and receiver are enabled.
+    ///
+    /// `initialize_gpio_pins` and `configure` remain available for boards
+    /// which need a different sequence.
+    pub fn bring_up(
+        &self,
+        tx: &gpio::GpioPin,
+        rx: &gpio::GpioPin,
+        params: hil::uart::Parameters,
+    ) -> Result<(), ErrorCode> {
         let regs = self.registers;
 
-        // Get a copy so we can check each interrupt flag in the register.
-        let pending_interrupts = regs.ip.extract();
+        self.can_configure(&params)?;
 
-        // Determine why an interrupt occurred.
-        if pending_interrupts.is_set(interrupt::txwm) {
-            // Got a TX interrupt which means the number of bytes in the FIFO
-            // has fallen to zero. If there is more to send do that, otherwise
-            // send a callback to the client.
-            if self.len.get() == self.index.get() {
-                // We are done.
-                regs.txctrl.write(txctrl::txen::CLEAR);
-                self.disable_tx_interrupt();
+        regs.txctrl.write(txctrl::txen::CLEAR);
+        regs.rxctrl.write(rxctrl::enable::CLEAR);
+        hil::uart::Configure::configure(self, params)?;
 
-                // Signal client write done
-                self.tx_client.map(|client| {
-                    self.buffer.take().map(|buffer| {
-                        client.transmitted_buffer(buffer, self.len.get(), Ok(()));
-                    });
-                });
-            } else {
-                // More to send. Fill the buffer until it is full.
-                self.buffer.map(|buffer| {
-                    for i in self.index.get()..self.len.get() {
-                        // Write the byte from the array to the tx register.
-                        regs.txdata.write(txdata::data.val(buffer[i] as u32));
-                        self.index.set(i + 1);
-                        // Check if the buffer is full
-                        if regs.txdata.is_set(txdata::full) {
-                            // If it is, break and wait for the TX interrupt.
-                            break;
-                        }
-                    }
-                });
-            }
+        self.initialize_gpio_pins(tx, rx);
+        self.delay_us(self.frame_time_us());
+
+        self.enable_transmitter();
+        regs.rxctrl
+            .write(rxctrl::enable::SET + rxctrl::counter.val(0));
+
+        Ok(())
+    }
+
+    /// Configure the UART from a descriptor built with `pack_config`, see
+    /// there for the layout.
+    ///
+    /// The descriptor is unpacked into `hil::uart::Parameters` and passed to
+    /// `configure`, with its errors. Returns `INVAL` if the baud rate index
+    /// is out of range or a reserved bit is set, without touching the
+    /// hardware.
+    pub fn configure_packed(&self, desc: u32) -> Result<(), ErrorCode> {
+        if desc & !(PACKED_BAUD_MASK | PACKED_TWO_STOP_BITS) != 0 {
+            return Err(ErrorCode::INVAL);
         }
+        let baud_rate = *PACKED_BAUD_RATES
+            .get((desc & PACKED_BAUD_MASK) as usize)
+            .ok_or(ErrorCode::INVAL)?;
+        let stop_bits = if desc & PACKED_TWO_STOP_BITS != 0 {
+            hil::uart::StopBits::Two
+        } else {
+            hil::uart::StopBits::One
+        };
+
+        hil::uart::Configure::configure(
+            self,
+            hil::uart::Parameters {
+                baud_rate,
+                width: hil::uart::Width::Eight,
+                parity: hil::uart::Parity::None,
+                stop_bits,
+                hw_flow_control: false,
+            },
+        )
     }
 
-    pub fn transmit_sync(&self, bytes: &[u8]) {
+    /// Measure the depth of the TX FIFO, and use it for the watermarks and
+    /// FIFO loops of the driver from then on, instead of assuming the
+    /// `FIFO_DEPTH` of the FE310. Returns the depth in use afterwards.
+    ///
+    /// The transmitter is disabled and `0xff` bytes are written to the TX
+    /// FIFO one at a time until `txdata.full` is set, counting how many fit;
+    /// the RX FIFO is assumed to be as deep. A detected depth larger than
+    /// `FIFO_DEPTH` is used as `FIFO_DEPTH`. If the FIFO never reports full
+    /// within `MAX_FIFO_DEPTH_PROBE` bytes, or is full from the start, the
+    /// depth is left unchanged.
+    ///
+    /// Bytes already waiting in the FIFO would be counted as taken slots, so
+    /// the transmitter must be idle: this returns `BUSY` without probing
+    /// while a transmit is in progress or the TX FIFO is not empty, e.g.
+    /// right after a `transmit_sync`. Try again once it has drained.
+    ///
+    /// The UART can't flush its TX FIFO, so the probe bytes are sent once
+    /// the transmitter is enabled again, which happens right away if it was
+    /// enabled before. `0xff` only shows up as a short low pulse (just the
+    /// start bit) on the line, but a receiver still sees bytes. Call this at
+    /// a quiet moment during initialization, before the pins are routed to
+    /// the UART or the peer listens, e.g. before `bring_up`.
+    pub fn detect_fifo_depth(&self) -> Result<usize, ErrorCode> {
         let regs = self.registers;
-        // Make sure the UART is enabled.
-        regs.txctrl
-            .write(txctrl::txen::SET + txctrl::nstop::OneStopBit + txctrl::txcnt.val(1));
-        for b in bytes.iter() {
-            while regs.txdata.is_set(txdata::full) {}
-            regs.txdata.write(txdata::data.val(*b as u32));
+
+        if self.tx_in_progress() {
+            return Err(ErrorCode::BUSY);
         }
-    }
-}
 
-impl hil::uart::Configure for Uart<'_> {
-    fn configure(&self, params: hil::uart::Parameters) -> Result<(), ErrorCode> {
-        // This chip does not support these features.
-        if params.parity != hil::uart::Parity::None {
-            return Err(ErrorCode::NOSUPPORT);
+        let txctrl = regs.txctrl.extract();
+        // With the watermark at 1, `txwm` is pending only while the TX FIFO
+        // is empty.
+        regs.txctrl.modify(txctrl::txcnt.val(1));
+        if !self.tx_fifo_empty() {
+            regs.txctrl.set(txctrl.get());
+            return Err(ErrorCode::BUSY);
         }
-        if params.hw_flow_control != false {
-            return Err(ErrorCode::NOSUPPORT);
+
+        regs.txctrl.modify(txctrl::txen::CLEAR);
+        let mut fitted = 0;
+        while fitted < MAX_FIFO_DEPTH_PROBE && !self.tx_fifo_full() {
+            self.push_tx_byte(0xff);
+            fitted += 1;
         }
+        regs.txctrl.set(txctrl.get());
 
-        // We can set the baud rate.
-        self.set_baud_rate(params.baud_rate);
+        if fitted > 0 && fitted < MAX_FIFO_DEPTH_PROBE {
+            self.fifo_depth.set(core::cmp::min(fitted, FIFO_DEPTH));
+        }
+        Ok(self.fifo_depth.get())
+    }
 
-        // We need to save the stop bits because it is set in the TX register.
-        self.stop_bits.set(params.stop_bits);
+    /// Provide the clock and alarm used by the timing-dependent features of
+    /// the driver, typically a virtual alarm on top of the CLINT. The UART
+    /// must also be set as the client of that alarm.
+    pub fn set_time_source(&self, time_source: &'a dyn TimeSource) {
+        self.time_source.set(time_source);
+    }
 
-        Ok(())
+    /// Provide the deferred call used by features which must not call back
+    /// into a client synchronously. `handle` is the handle returned when
+    /// registering this UART with `deferred_caller`.
+    pub fn set_deferred_call(
+        &self,
+        deferred_caller: &'static DynamicDeferredCall,
+        handle: DeferredCallHandle,
+    ) {
+        self.deferred_caller.set(deferred_caller);
+        self.deferred_handle.set(handle);
     }
-}
 
-impl<'a> hil::uart::Transmit<'a> for Uart<'a> {
-    fn set_transmit_client(&self, client: &'a dyn hil::uart::TransmitClient) {
-        self.tx_client.set(client);
+    /// Schedule a deferred call, returning `false` if none is available.
+    fn schedule_deferred_call(&self) -> bool {
+        self.deferred_caller
+            .and_then(|caller| self.deferred_handle.map(|handle| caller.set(*handle)))
+            .is_some()
     }
 
-    fn transmit_buffer(
-        &self,
-        tx_data: &'static mut [u8],
-        tx_len: usize,
-    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+    /// Select how `transmit_sync` waits for room in the TX FIFO.
+    pub fn set_transmit_sync_strategy(&self, strategy: TransmitSyncStrategy) {
+        self.transmit_sync_strategy.set(strategy);
+    }
+
+    /// Bits on the wire per byte: start bit, 8 data bits and the stop bits.
+    fn frame_bits(&self) -> u32 {
+        match self.stop_bits.get() {
+            hil::uart::StopBits::One => 10,
+            hil::uart::StopBits::Two => 11,
+        }
+    }
+
+    /// Time it takes to shift out one frame at the configured baud rate, in
+    /// microseconds, rounded up.
+    fn frame_time_us(&self) -> u32 {
+        let baud_rate = self.baud_rate.get();
+        (self.frame_bits() * 1_000_000 + baud_rate - 1) / baud_rate
+    }
+
+    /// Estimate how long until the active transmit has completely left the
+    /// UART, in microseconds. Returns `None` if no transmit is outstanding.
+    ///
+    /// The estimate covers the bytes the driver has not yet written to the
+    /// FIFO plus the bytes written by the last FIFO refill, each taking one
+    /// frame time at the configured baud rate. The UART can't report its FIFO
+    /// level, so the FIFO share is an upper bound that ignores bytes sent
+    /// since the refill. It assumes the line runs at full speed and does not
+    /// account for stalls, e.g. from chunking or flow control by a peer.
+    pub fn time_remaining_us(&self) -> Option<u32> {
+        if !self.tx_in_progress() {
+            return None;
+        }
+
+        let bytes = (self.len.get() - self.index.get() + self.tx_fifo_bytes.get()) as u64;
+        let us = bytes * self.frame_bits() as u64 * 1_000_000 / self.baud_rate.get() as u64;
+        Some(core::cmp::min(us, u32::MAX as u64) as u32)
+    }
+
+    /// Busy-wait for `us` microseconds using the time source. Returns
+    /// `false` without waiting if no time source is available.
+    fn delay_us(&self, us: u32) -> bool {
+        self.time_source.map_or(false, |time| {
+            let start = time.now();
+            let dt = time.us_to_ticks(us);
+            while time.now().wrapping_sub(start) < dt {}
+            true
+        })
+    }
+
+    /// Wait for `txwm` with the TX watermark at 1, i.e. for the TX FIFO to
+    /// empty. Returns `FAIL` if it hasn't after as many polls as the UART
+    /// clock ticks while a full FIFO drains at the configured baud rate. A
+    /// poll takes at least one clock cycle, so a FIFO that is still draining
+    /// never runs out of polls.
+    fn wait_tx_fifo_empty(&self) -> Result<(), ErrorCode> {
         let regs = self.registers;
+        let polls =
+            self.fifo_depth.get() as u64 * self.frame_bits() as u64 * self.uart_clock() as u64
+                / self.baud_rate.get() as u64;
 
-        if tx_len == 0 {
-            return Err((ErrorCode::SIZE, tx_data));
+        for _ in 0..polls {
+            if regs.ip.is_set(interrupt::txwm) {
+                return Ok(());
+            }
         }
+        Err(ErrorCode::FAIL)
+    }
 
-        // Enable the interrupt so we know when we can keep writing.
-        self.enable_tx_interrupt();
+    /// Wait until the TX FIFO has room for another byte, using the
+    /// configured `TransmitSyncStrategy`.
+    fn wait_tx_fifo_not_full(&self) {
+        let regs = self.registers;
 
-        // Fill the TX buffer until it reports full.
-        for i in 0..tx_len {
-            // Write the byte from the array to the tx register.
-            regs.txdata.write(txdata::data.val(tx_data[i] as u32));
-            self.index.set(i + 1);
-            // Check if the buffer is full
-            if regs.txdata.is_set(txdata::full) {
-                // If it is, break and wait for the TX interrupt.
-                break;
+        while regs.txdata.is_set(txdata::full) {
+            match self.transmit_sync_strategy.get() {
+                TransmitSyncStrategy::Spin => {}
+                TransmitSyncStrategy::Backoff { nops } => {
+                    for _ in 0..nops {
+                        rv32i::support::nop();
+                    }
+                }
+                TransmitSyncStrategy::DrainDelay => {
+                    // The FIFO was full, so it will take this long until it
+                    // is empty again.
+                    self.delay_us(self.frame_time_us() * self.fifo_depth.get() as u32);
+                }
             }
         }
+    }
+
+    /// Write the divisor register, unless a test has the host stream drop
+    /// the write.
+    fn write_div(&self, divisor: u16) {
+        #[cfg(test)]
+        if self.host.map_or(false, |host| host.drop_div_write()) {
+            return;
+        }
 
-        // Save the buffer so we can keep sending it.
-        self.buffer.replace(tx_data);
-        self.len.set(tx_len);
+        self.registers.div.write(div::div.val(divisor as u32));
+    }
 
-        // Enable transmissions, and wait until the FIFO is empty before getting
-        // an interrupt.
-        let stop_bits = match self.stop_bits.get() {
-            hil::uart::StopBits::One => txctrl::nstop::OneStopBit,
-            hil::uart::StopBits::Two => txctrl::nstop::TwoStopBits,
-        };
-        regs.txctrl
-            .write(txctrl::txen::SET + stop_bits + txctrl::txcnt.val(1));
+    fn set_baud_rate(&self, baud_rate: u32) -> Result<(), ErrorCode> {
+        let regs = self.registers;
+
+        let divisor = baud::divisor(self.uart_clock(), baud_rate).ok_or(ErrorCode::INVAL)?;
+
+        self.write_div(divisor);
+        if self.div_write_verify.get() {
+            let mut attempts = 1;
+            while regs.div.read(div::div) != divisor as u32 {
+                if attempts == MAX_DIV_WRITE_ATTEMPTS {
+                    return Err(ErrorCode::FAIL);
+                }
+                self.write_div(divisor);
+                attempts += 1;
+            }
+        }
+        self.baud_rate.set(baud_rate);
+        self.expected_div.set(divisor);
+
+        #[cfg(feature = "diagnostics")]
+        self.record_config_change(ConfigChange::Divisor(divisor));
 
         Ok(())
     }
 
-    fn transmit_abort(&self) -> Result<(), ErrorCode> {
-        Err(ErrorCode::FAIL)
+    /// Read `div` back after writing it whenever the baud rate is set, and
+    /// rewrite it until the value sticks, up to `MAX_DIV_WRITE_ATTEMPTS`
+    /// times in total.
+    ///
+    /// This guards against bus fabrics which occasionally drop an MMIO
+    /// write during a stall, which for the divisor would leave the UART at
+    /// the wrong baud rate for good. If the divisor never reads back
+    /// correctly, setting the baud rate (as done by `configure`,
+    /// `adjust_for_drift`, `transmit_at_baud` and `recover`) fails with
+    /// `FAIL`, and the previous baud rate stays recorded as the configured
+    /// one, while `div` is left holding whatever it reads back as. This is
+    /// off by default since it costs an extra MMIO read on every divisor
+    /// write.
+    pub fn set_write_verify(&self, verify: bool) {
+        self.div_write_verify.set(verify);
     }
 
-    fn transmit_word(&self, _word: u32) -> Result<(), ErrorCode> {
-        Err(ErrorCode::FAIL)
+    /// The frequency the UART clock actually runs at, i.e. the one passed
+    /// to `new` corrected by the last `adjust_for_drift`.
+    fn uart_clock(&self) -> u32 {
+        let nominal = self.clock_frequency as i64;
+        let corrected = nominal + nominal * self.clock_drift_ppm.get() as i64 / 1_000_000;
+        corrected as u32
     }
-}
 
-impl<'a> hil::uart::Receive<'a> for Uart<'a> {
-    fn set_receive_client(&self, client: &'a dyn hil::uart::ReceiveClient) {
-        self.rx_client.set(client);
+    /// Compensate for the UART clock drifting away from its nominal
+    /// frequency, e.g. a cheap crystal over temperature.
+    ///
+    /// `measured_ppm` is how far the clock currently is off from the
+    /// frequency passed to `new`, in parts per million, positive if it runs
+    /// fast; it is typically estimated from a temperature sensor. Each call
+    /// replaces the previous correction rather than adding to it. The
+    /// divisor is recomputed to get closest to the configured baud rate
+    /// from the corrected clock frequency, `nominal * (1 + ppm / 10^6)`, and
+    /// `div` is rewritten if that changes it. The correction is also used
+    /// by later calls to `configure`. Since the divisor is an integer, a
+    /// correction only takes effect once it amounts to a whole step of it,
+    /// which at high baud rates can be thousands of ppm.
+    ///
+    /// Rewriting `div` mid-frame would corrupt that frame, so this returns
+    /// `BUSY` while a transmit or receive is in progress and changes
+    /// nothing; try again later. Returns `INVAL` if the corrected frequency
+    /// can't produce the configured baud rate or `measured_ppm` is not
+    /// within ±500000.
+    pub fn adjust_for_drift(&self, measured_ppm: i32) -> Result<(), ErrorCode> {
+        if !(-500_000..=500_000).contains(&measured_ppm) {
+            return Err(ErrorCode::INVAL);
+        }
+        if self.tx_in_progress() || self.rx_busy() {
+            return Err(ErrorCode::BUSY);
+        }
+
+        let previous = self.clock_drift_ppm.replace(measured_ppm);
+        if !self.configured.get() {
+            return Ok(());
+        }
+        if baud::divisor(self.uart_clock(), self.baud_rate.get()) == self.expected_div.extract() {
+            return Ok(());
+        }
+        self.set_baud_rate(self.baud_rate.get()).map_err(|e| {
+            self.clock_drift_ppm.set(previous);
+            e
+        })
     }
 
-    fn receive_buffer(
-        &self,
-        rx_buffer: &'static mut [u8],
-        _rx_len: usize,
-    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
-        Err((ErrorCode::FAIL, rx_buffer))
+    /// Register a waker to be woken whenever a `transmit_buffer` completes.
+    ///
+    /// This lets an external async executor find out about completions
+    /// without the driver owning a future. Only a single waker is stored:
+    /// registering a new one, even in the middle of a transfer, replaces
+    /// (and drops) the previous one, and only the new waker is woken when the
+    /// transfer completes. The waker stays registered across completions.
+    /// The `TransmitClient` callback is still issued as usual.
+    #[cfg(feature = "async")]
+    pub fn register_tx_waker(&self, waker: &core::task::Waker) {
+        let replace = self
+            .tx_waker
+            .map_or(true, |registered| !registered.will_wake(waker));
+        if replace {
+            self.tx_waker.replace(waker.clone());
+        }
     }
 
-    fn receive_abort(&self) -> Result<(), ErrorCode> {
-        Err(ErrorCode::FAIL)
+    /// Number of bytes written to the TX FIFO since the last reset of the
+    /// statistics, wrapping on overflow.
+    #[cfg(feature = "diagnostics")]
+    pub fn tx_byte_count(&self) -> u32 {
+        self.diagnostics.tx_bytes.get()
     }
 
-    fn receive_word(&self) -> Result<(), ErrorCode> {
-        Err(ErrorCode::FAIL)
+    /// Number of bytes drained from the RX FIFO since the last reset of the
+    /// statistics, wrapping on overflow.
+    #[cfg(feature = "diagnostics")]
+    pub fn rx_byte_count(&self) -> u32 {
+        self.diagnostics.rx_bytes.get()
+    }
+
+    /// Number of times `handle_interrupt` ran without any enabled interrupt
+    /// being pending.
+    #[cfg(feature = "diagnostics")]
+    pub fn spurious_interrupt_count(&self) -> u32 {
+        self.diagnostics.spurious_interrupts.get()
+    }
+
+    /// Largest number of bytes drained from the RX FIFO in one interrupt. A
+    /// value close to the FIFO depth means the interrupt is serviced too
+    /// late and bytes are at risk of being overrun.
+    #[cfg(feature = "diagnostics")]
+    pub fn peak_rx_backlog(&self) -> usize {
+        self.diagnostics.peak_rx_backlog.get()
+    }
+
+    /// Reset all statistics to zero.
+    #[cfg(feature = "diagnostics")]
+    pub fn reset_diagnostics(&self) {
+        self.diagnostics.tx_bytes.set(0);
+        self.diagnostics.rx_bytes.set(0);
+        self.diagnostics.spurious_interrupts.set(0);
+        self.diagnostics.peak_rx_backlog.set(0);
+        self.diagnostics.wakeups.set(0);
+        self.reset_completed_transmits();
+        self.reset_completed_receives();
+    }
+
+    /// Note that the core just woke up from sleep.
+    ///
+    /// For `wakeup_count` to mean anything, the chip's sleep routine must
+    /// call this for the UART right after `wfi` returns, before interrupts
+    /// are serviced. If an enabled UART interrupt is pending at that point,
+    /// it is taken to have caused the wakeup, and the next
+    /// `handle_interrupt` counts it as one.
+    #[cfg(feature = "diagnostics")]
+    pub fn mark_wakeup(&self) {
+        let regs = self.registers;
+        self.diagnostics
+            .woken
+            .set(regs.ip.get() & regs.ie.get() != 0);
+    }
+
+    /// Number of UART interrupts which woke the core from sleep, as opposed
+    /// to ones which arrived while it was already awake, since the last
+    /// reset of the statistics. See `mark_wakeup`.
+    #[cfg(feature = "diagnostics")]
+    pub fn wakeup_count(&self) -> u32 {
+        self.diagnostics.wakeups.get()
+    }
+
+    /// Number of transmits which completed successfully since the last
+    /// reset, wrapping on overflow. Each successful completion callback
+    /// counts once, whether for `transmit_buffer`, a constant or streamed
+    /// transmit, or an exchange. Comparing this with the number of
+    /// transmits a capsule started shows whether a callback went missing.
+    #[cfg(feature = "diagnostics")]
+    pub fn completed_transmits(&self) -> u32 {
+        self.diagnostics.completed_transmits.get()
+    }
+
+    /// Number of receives which completed successfully since the last
+    /// reset, wrapping on overflow; the receive counterpart of
+    /// `completed_transmits`.
+    #[cfg(feature = "diagnostics")]
+    pub fn completed_receives(&self) -> u32 {
+        self.diagnostics.completed_receives.get()
+    }
+
+    /// Reset `completed_transmits` to zero, leaving the other statistics
+    /// alone.
+    #[cfg(feature = "diagnostics")]
+    pub fn reset_completed_transmits(&self) {
+        self.diagnostics.completed_transmits.set(0);
+    }
+
+    /// Reset `completed_receives` to zero, leaving the other statistics
+    /// alone.
+    #[cfg(feature = "diagnostics")]
+    pub fn reset_completed_receives(&self) {
+        self.diagnostics.completed_receives.set(0);
+    }
+
+    /// The most recent configuration changes, oldest first.
+    ///
+    /// The driver keeps the last `CONFIG_HISTORY_LEN` calls to `configure`
+    /// and writes of the divisor register (which `configure` and `recover`
+    /// do) in a ring. Once the ring is full each new change overwrites the
+    /// oldest entry. This is not cleared by `reset_diagnostics`.
+    #[cfg(feature = "diagnostics")]
+    pub fn config_history(&self) -> impl Iterator<Item = ConfigEvent> {
+        let history = self.diagnostics.config_history.get();
+        let next = self.diagnostics.config_history_next.get();
+        (0..CONFIG_HISTORY_LEN).filter_map(move |i| history[(next + i) % CONFIG_HISTORY_LEN])
+    }
+
+    /// Take a snapshot of the driver state, e.g. for a panic handler to
+    /// print when the UART is involved in a hang ("transmit stuck at index
+    /// 3 of 64"). Only reads the state, so it is safe to call at any time.
+    #[cfg(feature = "diagnostics")]
+    pub fn debug_state(&self) -> UartDebugState {
+        let regs = self.registers;
+        UartDebugState {
+            name: self.name(),
+            tx_in_progress: self.tx_in_progress(),
+            tx_buffer_held: self.buffer.is_some(),
+            tx_index: self.index.get(),
+            tx_len: self.len.get(),
+            rx_buffer_held: self.rx_buffer.is_some(),
+            rx_index: self.rx_index.get(),
+            rx_len: self.rx_len.get(),
+            baud_rate: if self.configured.get() {
+                self.baud_rate.get()
+            } else {
+                0
+            },
+            stop_bits: self.stop_bits.get(),
+            ie: regs.ie.get(),
+            ip: regs.ip.get(),
+        }
+    }
+
+    /// Copy every byte read from the RX FIFO into `tap`, for looking at
+    /// what actually arrived on a flaky link.
+    ///
+    /// The tap sees the raw bytes before any of the receive modes act on
+    /// them, including bytes those discard, such as line noise before a
+    /// prefix, overflowing RTU frames, loopback echoes and bytes dropped by
+    /// `recover`. It is a ring: once full, each new byte overwrites the
+    /// oldest one. The tap is purely observational and does not change what
+    /// clients receive. Attaching a tap replaces, and returns, the previous
+    /// one; an empty `tap` is rejected and handed back.
+    #[cfg(feature = "diagnostics")]
+    pub fn attach_rx_tap(&self, tap: &'static mut [u8]) -> Option<&'static mut [u8]> {
+        if tap.is_empty() {
+            return Some(tap);
+        }
+        self.diagnostics.rx_tap_written.set(0);
+        self.diagnostics.rx_tap.replace(tap)
+    }
+
+    /// Remove the tap set with `attach_rx_tap` and return it for dumping.
+    /// The oldest byte is at `rx_tap_written() % tap.len()` once the ring
+    /// has wrapped, and at index 0 before.
+    #[cfg(feature = "diagnostics")]
+    pub fn detach_rx_tap(&self) -> Option<&'static mut [u8]> {
+        self.diagnostics.rx_tap.take()
+    }
+
+    /// Number of bytes written to the tap since it was attached, wrapping on
+    /// overflow.
+    #[cfg(feature = "diagnostics")]
+    pub fn rx_tap_written(&self) -> usize {
+        self.diagnostics.rx_tap_written.get()
+    }
+
+    #[cfg(feature = "diagnostics")]
+    fn record_config_change(&self, change: ConfigChange) {
+        let diagnostics = &self.diagnostics;
+        let mut history = diagnostics.config_history.get();
+        let next = diagnostics.config_history_next.get();
+        history[next] = Some(ConfigEvent {
+            name: self.name(),
+            change,
+            timestamp: self.time_source.map(|time| time.now()),
+        });
+        diagnostics.config_history.set(history);
+        diagnostics
+            .config_history_next
+            .set((next + 1) % CONFIG_HISTORY_LEN);
+    }
+
+    /// Write one byte to the TX FIFO hardware, or to the host stream if
+    /// there is one.
+    fn push_tx_byte(&self, byte: u8) {
+        let regs = self.registers;
+
+        #[cfg(any(test, feature = "std"))]
+        if let Some(host) = self.host.extract() {
+            return host.push_tx(byte);
+        }
+
+        regs.txdata.write(txdata::data.val(byte as u32));
+    }
+
+    /// Whether the TX FIFO, of the hardware or the host stream, is full.
+    fn tx_fifo_full(&self) -> bool {
+        #[cfg(any(test, feature = "std"))]
+        if let Some(host) = self.host.extract() {
+            return host.tx_fifo_full();
+        }
+
+        self.registers.txdata.is_set(txdata::full)
+    }
+
+    /// Whether the TX FIFO, of the hardware or the host stream, is empty.
+    /// The TX watermark must be at 1.
+    fn tx_fifo_empty(&self) -> bool {
+        #[cfg(any(test, feature = "std"))]
+        if let Some(host) = self.host.extract() {
+            return host.tx_fifo_empty();
+        }
+
+        self.registers.ip.is_set(interrupt::txwm)
+    }
+
+    /// Write one byte to the TX FIFO.
+    fn write_tx_byte(&self, byte: u8) {
+        self.push_tx_byte(byte);
+
+        #[cfg(feature = "diagnostics")]
+        self.diagnostics
+            .tx_bytes
+            .set(self.diagnostics.tx_bytes.get().wrapping_add(1));
+    }
+
+    /// Like `transmit_buffer`, but choosing how this transmit progresses.
+    ///
+    /// With `TransferMode::Polled` the TX interrupt stays disabled, so the
+    /// transmit keeps going while interrupts are masked, e.g. around a
+    /// critical section. It fills the FIFO once, then schedules the deferred
+    /// call set with `set_deferred_call`. Each time the kernel runs that
+    /// call it checks `txwm`, refills the FIFO if it has emptied and, unless
+    /// the transmit is done, schedules itself again for the next pass of the
+    /// kernel loop. The client is called from that deferred call. Polled
+    /// transmits return `OFF` if no deferred call is available, and are not
+    /// checked by `enable_tx_verify`.
+    ///
+    /// Returns `SIZE` if `tx_len` is zero or larger than `tx_data`.
+    pub fn transmit_buffer_mode(
+        &self,
+        tx_data: &'static mut [u8],
+        tx_len: usize,
+        mode: TransferMode,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if tx_len == 0 || tx_len > tx_data.len() {
+            return Err((ErrorCode::SIZE, tx_data));
+        }
+        if let Err(e) = self.transmit_ready() {
+            return Err((e, tx_data));
+        }
+        if mode == TransferMode::Polled && self.deferred_handle.is_none() {
+            return Err((ErrorCode::OFF, tx_data));
+        }
+
+        // Save the buffer so we can keep sending it.
+        self.buffer.replace(tx_data);
+        self.len.set(tx_len);
+        self.index.set(0);
+        self.tx_polled.set(mode == TransferMode::Polled);
+        self.tx_delimiter_pending
+            .set(self.tx_frame_delimiter.get().is_some());
+
+        self.start_transmit();
+
+        Ok(())
+    }
+
+    /// Transmit `tx_len` bytes of `tx_data` paced by an external signal, one
+    /// byte for each call to `strobe`.
+    ///
+    /// This is for protocols where each byte is clocked out on an external
+    /// strobe, with `strobe` called from the interrupt of that GPIO edge or
+    /// whatever else drives it. No byte is sent until the first strobe.
+    /// Each byte is written to the TX FIFO only when its strobe arrives, so
+    /// the FIFO never holds more than what was strobed and doesn't buffer
+    /// ahead; a byte starts going out as soon as the one before it has left
+    /// the shift register. The `TransmitClient` is called once the last
+    /// byte has been sent. Frame delimiters, the rate limit and transmit
+    /// verification do not apply.
+    ///
+    /// Returns `SIZE` if `tx_len` is zero or larger than the buffer, and
+    /// `BUSY` if a transmit is already in progress.
+    pub fn transmit_strobed(
+        &self,
+        tx_data: &'static mut [u8],
+        tx_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if tx_len == 0 || tx_len > tx_data.len() {
+            return Err((ErrorCode::SIZE, tx_data));
+        }
+        if let Err(e) = self.transmit_ready() {
+            return Err((e, tx_data));
+        }
+
+        self.buffer.replace(tx_data);
+        self.len.set(tx_len);
+        self.index.set(0);
+        self.tx_polled.set(false);
+        if self.tx_null.get() {
+            self.start_transmit();
+            return Ok(());
+        }
+        self.tx_strobed.set(true);
+        self.tx_verify_active.set(false);
+        self.tx_delimiter_pending.set(false);
+        self.tx_chunk_end.set(tx_len);
+        self.tx_fifo_bytes.set(0);
+
+        self.enable_transmitter();
+
+        Ok(())
+    }
+
+    /// Send the next byte of a `transmit_strobed` transmit.
+    ///
+    /// Does nothing if no strobed transmit is in progress or all of its
+    /// bytes have been sent already. If the TX FIFO is full, which only
+    /// happens if strobes arrive faster than a frame each, the byte is held
+    /// back for the next strobe.
+    pub fn strobe(&self) {
+        let regs = self.registers;
+
+        if !self.tx_strobed.get() || self.index.get() >= self.len.get() {
+            return;
+        }
+        if regs.txdata.is_set(txdata::full) {
+            return;
+        }
+
+        let index = self.index.get();
+        if let Some(byte) = self.buffer.map(|buffer| buffer[index]) {
+            self.write_tx_byte(byte);
+            self.index.set(index + 1);
+            // There is no refill to start counting from, but the FIFO can't
+            // hold more than its depth.
+            self.tx_fifo_bytes.set(core::cmp::min(
+                self.tx_fifo_bytes.get() + 1,
+                self.fifo_depth.get(),
+            ));
+        }
+        if self.index.get() == self.len.get() {
+            // Complete once the FIFO has drained, through the TX interrupt.
+            self.enable_tx_interrupt();
+        }
+    }
+
+    /// Transmit `tx_len` bytes of `tx_buffer` at a different baud rate,
+    /// e.g. a burst at a speed negotiated with the peer, then go back to
+    /// the configured one.
+    ///
+    /// The divisor is switched to `baud` and the buffer is sent like with
+    /// `transmit_buffer`. Once the FIFO has drained, the driver sets the
+    /// alarm one frame time ahead for the last byte to leave the shift
+    /// register, restores the previous baud rate from the alarm and only
+    /// then calls the `TransmitClient`, so the client can rely on the
+    /// previous rate being back in place. `recover` and
+    /// `prepare_for_update` restore it as well.
+    ///
+    /// The baud rate applies to the receiver too, so any byte received
+    /// during the burst or across the switches is garbled. This therefore
+    /// requires RX to be idle and returns `BUSY` if a receive is posted,
+    /// as well as if a transmit is in progress. Returns `NOSUPPORT` without
+    /// a time source, `INVAL` if `baud` can't be derived from the clock or
+    /// the UART is not configured yet, and the errors of `transmit_buffer`;
+    /// the baud rate is unchanged in that case.
+    pub fn transmit_at_baud(
+        &self,
+        tx_buffer: &'static mut [u8],
+        tx_len: usize,
+        baud: u32,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.time_source.is_none() {
+            return Err((ErrorCode::NOSUPPORT, tx_buffer));
+        }
+        if !self.configured.get() || baud::divisor(self.uart_clock(), baud).is_none() {
+            return Err((ErrorCode::INVAL, tx_buffer));
+        }
+        if self.rx_busy() {
+            return Err((ErrorCode::BUSY, tx_buffer));
+        }
+        if let Err(e) = self.transmit_ready() {
+            return Err((e, tx_buffer));
+        }
+
+        let previous = self.baud_rate.get();
+        if let Err(e) = self.set_baud_rate(baud) {
+            return Err((e, tx_buffer));
+        }
+        self.tx_restore_baud.set(previous);
+        self.transmit_buffer_mode(tx_buffer, tx_len, TransferMode::Interrupt)
+            .map_err(|e| {
+                self.restore_baud();
+                e
+            })
+    }
+
+    /// Go back to the baud rate from before `transmit_at_baud`, if it
+    /// changed it. The caller makes sure the line is idle.
+    fn restore_baud(&self) {
+        self.tx_restore_deadline.clear();
+        if let Some(baud) = self.tx_restore_baud.take() {
+            // This rate was in use before, so it can be derived again.
+            let _ = self.set_baud_rate(baud);
+        }
+    }
+
+    /// Set the client for `transmit_then_receive`.
+    pub fn set_transaction_client(&self, client: &'a dyn TransactionClient) {
+        self.txn_client.set(client);
+    }
+
+    /// Send a request and receive the response to it, e.g. a command to a
+    /// sensor or modem and its reply, with a single callback to the
+    /// `TransactionClient` at the end.
+    ///
+    /// `tx_len` bytes of `tx_buffer` are transmitted like with
+    /// `transmit_buffer`. Once that has completed, a receive of `rx_len`
+    /// bytes into `rx_buffer` is posted, so a response which starts right
+    /// after the request isn't missed as long as it fits in the RX FIFO in
+    /// the meantime. If `timeout_us` is given, the receive is ended that
+    /// long after it was posted if the response hasn't fully arrived; this
+    /// needs a time source. The `TransmitClient` and `ReceiveClient` are
+    /// not called for the exchange.
+    ///
+    /// If the transmit fails, no receive is posted and the error is
+    /// reported right away. Returns the error and both buffers if the
+    /// exchange can't be started: `SIZE` for a bad length, `BUSY` if a
+    /// transmit or receive is already active, `NOSUPPORT` if `timeout_us`
+    /// is given without a time source.
+    pub fn transmit_then_receive(
+        &self,
+        tx_buffer: &'static mut [u8],
+        tx_len: usize,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+        timeout_us: Option<u32>,
+    ) -> Result<(), (ErrorCode, &'static mut [u8], &'static mut [u8])> {
+        if rx_len == 0 || rx_len > rx_buffer.len() {
+            return Err((ErrorCode::SIZE, tx_buffer, rx_buffer));
+        }
+        if self.rx_busy() || self.txn.get() != Transaction::Idle {
+            return Err((ErrorCode::BUSY, tx_buffer, rx_buffer));
+        }
+        if timeout_us.is_some() && self.time_source.is_none() {
+            return Err((ErrorCode::NOSUPPORT, tx_buffer, rx_buffer));
+        }
+
+        self.txn.set(Transaction::Transmitting);
+        if let Err((e, tx_buffer)) =
+            self.transmit_buffer_mode(tx_buffer, tx_len, TransferMode::Interrupt)
+        {
+            self.txn.set(Transaction::Idle);
+            return Err((e, tx_buffer, rx_buffer));
+        }
+        self.txn_rx_buffer.replace(rx_buffer);
+        self.txn_rx_len.set(rx_len);
+        self.txn_timeout_us.set(timeout_us);
+        Ok(())
+    }
+
+    /// The request of an exchange has been transmitted, post the receive
+    /// for the response.
+    fn transaction_transmitted(&self, tx_buffer: &'static mut [u8], rval: Result<(), ErrorCode>) {
+        let rx_buffer = match self.txn_rx_buffer.take() {
+            Some(rx_buffer) => rx_buffer,
+            None => return,
+        };
+
+        let rval = rval.and_then(|()| {
+            self.start_receive(rx_buffer, self.txn_rx_len.get(), RxMode::Buffer)
+                .map_err(|(e, rx_buffer)| {
+                    self.txn_rx_buffer.replace(rx_buffer);
+                    e
+                })
+        });
+        match rval {
+            Ok(()) => {
+                self.txn_tx_buffer.replace(tx_buffer);
+                self.txn.set(Transaction::Receiving);
+                self.start_txn_timeout();
+            }
+            Err(e) => {
+                self.txn.set(Transaction::Idle);
+                let rx_buffer = self.txn_rx_buffer.take().unwrap_or(&mut []);
+                self.txn_client.map(|client| {
+                    client.transaction_done(
+                        tx_buffer,
+                        rx_buffer,
+                        0,
+                        Err(e),
+                        hil::uart::Error::None,
+                    );
+                });
+            }
+        }
+    }
+
+    /// Start the timeout of the exchange in progress, if it has one.
+    fn start_txn_timeout(&self) {
+        if let Some(us) = self.txn_timeout_us.get() {
+            self.time_source.map(|time| {
+                self.txn_deadline
+                    .set(time.now().wrapping_add(time.us_to_ticks(us)));
+            });
+            self.arm_alarm();
+        }
+    }
+
+    /// The response of an exchange has been received, or the receive ended.
+    fn transaction_received(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rval: Result<(), ErrorCode>,
+        error: hil::uart::Error,
+    ) {
+        self.txn.set(Transaction::Idle);
+        self.txn_deadline.clear();
+        if let Some(tx_buffer) = self.txn_tx_buffer.take() {
+            self.txn_client.map(|client| {
+                client.transaction_done(tx_buffer, rx_buffer, self.rx_index.get(), rval, error);
+            });
+        }
+    }
+
+    /// Transmit `tx_len` bytes of `tx_buffer`, but only report the transmit
+    /// as done once the peer has acknowledged it with `ack_byte`, for
+    /// half-duplex protocols with single-byte ACKs.
+    ///
+    /// The exchange goes through these states:
+    /// 1. Stale bytes are dropped from the RX FIFO, and the data is sent
+    ///    like with `transmit_buffer`. If that fails, the `TransmitClient`
+    ///    gets the error right away.
+    /// 2. Once all data has been sent, RX is watched for up to `timeout_us`
+    ///    microseconds. The acknowledgment only counts if it arrives after
+    ///    the data has gone out.
+    /// 3. The `TransmitClient` is called with `Ok(())` as soon as `ack_byte`
+    ///    is received, or with `Err(ErrorCode::FAIL)` if the timeout expires
+    ///    first or the byte set with `set_nak_byte` is received.
+    ///
+    /// Any other bytes received while waiting, e.g. line noise or the echo
+    /// of a half-duplex transceiver, are discarded and the wait goes on.
+    /// The transmit counts as in progress until the end, so other transmits
+    /// return `BUSY` meanwhile. Receives can't be posted either, as the RX
+    /// FIFO is used for the acknowledgment. Returns `BUSY` if a transfer is
+    /// in progress, `NOSUPPORT` if there is no time source for the timeout,
+    /// and the errors of `transmit_buffer`.
+    pub fn transmit_then_await_ack(
+        &self,
+        tx_buffer: &'static mut [u8],
+        tx_len: usize,
+        ack_byte: u8,
+        timeout_us: u32,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.time_source.is_none() {
+            return Err((ErrorCode::NOSUPPORT, tx_buffer));
+        }
+        if self.rx_busy() || self.txn.get() != Transaction::Idle {
+            return Err((ErrorCode::BUSY, tx_buffer));
+        }
+        if let Err(e) = self.transmit_ready() {
+            return Err((e, tx_buffer));
+        }
+
+        self.discard_stale_rx();
+        self.txn_ack.set(ack_byte);
+        self.txn_timeout_us.set(Some(timeout_us));
+        self.txn.set(Transaction::AckTransmitting);
+        self.transmit_buffer_mode(tx_buffer, tx_len, TransferMode::Interrupt)
+            .map_err(|e| {
+                self.txn.set(Transaction::Idle);
+                e
+            })
+    }
+
+    /// Set a byte which makes `transmit_then_await_ack` fail right away
+    /// when it is received instead of the acknowledgment, or `None`, the
+    /// default, to only fail on the timeout.
+    pub fn set_nak_byte(&self, nak_byte: Option<u8>) {
+        self.txn_nak.set(nak_byte);
+    }
+
+    /// The data of a `transmit_then_await_ack` has been sent, start
+    /// watching RX for the acknowledgment.
+    fn await_ack(&self) {
+        let regs = self.registers;
+
+        self.txn.set(Transaction::AwaitingAck);
+        self.start_txn_timeout();
+        regs.rxctrl
+            .write(rxctrl::enable::SET + rxctrl::counter.val(0));
+        self.enable_rx_interrupt();
+    }
+
+    /// Look for the acknowledgment among the received bytes.
+    fn service_ack(&self) {
+        for _ in 0..self.fifo_depth.get() {
+            match self.read_rx_fifo() {
+                Some(byte) if byte == self.txn_ack.get() => {
+                    return self.finish_ack(Ok(()));
+                }
+                Some(byte) if Some(byte) == self.txn_nak.get() => {
+                    return self.finish_ack(Err(ErrorCode::FAIL));
+                }
+                Some(_) => {}
+                None => return,
+            }
+        }
+    }
+
+    /// End a `transmit_then_await_ack`, reporting `rval` to the client.
+    fn finish_ack(&self, rval: Result<(), ErrorCode>) {
+        self.txn.set(Transaction::Idle);
+        self.txn_deadline.clear();
+        self.release_rx();
+        self.complete_transmit(rval);
+    }
+
+    /// Make progress on a polled transmit, see `transmit_buffer_mode`.
+    fn poll_transmit(&self) {
+        let regs = self.registers;
+
+        if regs.ip.is_set(interrupt::txwm) {
+            self.service_tx();
+        }
+        if self.tx_polled.get() {
+            self.schedule_deferred_call();
+        }
+    }
+
+    /// Set the client for transmits started with `transmit_const`.
+    pub fn set_transmit_const_client(&self, client: &'a dyn TransmitConstClient) {
+        self.tx_const_client.set(client);
+    }
+
+    /// Transmit immutable `'static` data, e.g. a banner stored in flash,
+    /// without copying it into a RAM buffer first.
+    ///
+    /// This uses the same interrupt-driven machinery as `transmit_buffer`
+    /// and is mutually exclusive with it (`BUSY` is returned while either is
+    /// outstanding). Completion is reported through
+    /// `TransmitConstClient::transmitted_const` instead of
+    /// `TransmitClient::transmitted_buffer`, since there is no buffer to
+    /// return.
+    pub fn transmit_const(&self, data: &'static [u8]) -> Result<(), ErrorCode> {
+        if data.is_empty() {
+            return Err(ErrorCode::SIZE);
+        }
+        self.transmit_ready()?;
+
+        self.tx_const.set(data);
+        self.len.set(data.len());
+        self.index.set(0);
+        self.start_transmit();
+        Ok(())
+    }
+
+    /// Split long transmits into chunks of `bytes` bytes, reporting each
+    /// finished chunk to the `TransmitProgressClient`.
+    ///
+    /// The driver stops refilling the FIFO at each chunk boundary, so every
+    /// chunk ends in its own TX interrupt, and calls `transmit_progress` from
+    /// that interrupt before continuing with the next chunk. This gives a
+    /// cooperative scheduler a regular hook during bulk transfers. Progress
+    /// callbacks do not hand over the buffer: it stays with the driver until
+    /// the usual completion callback, which still fires at the end (the last
+    /// chunk is only reported through it). A `bytes` of 0, the default,
+    /// disables chunking. A new size takes effect at the next chunk boundary.
+    pub fn set_tx_chunk(&self, bytes: usize) {
+        self.tx_chunk.set(bytes);
+    }
+
+    /// Where the chunk starting at byte `start` of a transmit ends.
+    fn next_chunk_end(&self, start: usize) -> usize {
+        match self.tx_chunk.get() {
+            0 => usize::MAX,
+            chunk => start.saturating_add(chunk),
+        }
+    }
+
+    /// Set the client for progress callbacks of chunked transmits.
+    pub fn set_tx_progress_client(&self, client: &'a dyn TransmitProgressClient) {
+        self.tx_progress_client.set(client);
+    }
+
+    /// Make transmits succeed without sending anything.
+    ///
+    /// In this mode `transmit_buffer` and `transmit_const` still validate
+    /// their arguments as usual, but instead of touching the hardware they
+    /// report completion of the whole buffer with `Ok(())`, as if it had
+    /// been transmitted instantly. This lets tests of capsules exercise the
+    /// full callback flow deterministically. The completion callback is
+    /// issued from a deferred call, never from within the transmit call
+    /// itself, as the HIL requires; transmits therefore return `OFF` in this
+    /// mode if no deferred call was provided with `set_deferred_call`.
+    pub fn set_null_transmit(&self, null: bool) {
+        self.tx_null.set(null);
+    }
+
+    /// Check every transmitted byte against its echo on RX.
+    ///
+    /// This is a bring-up aid which requires TX to be looped back to RX
+    /// externally, e.g. with a jumper. Each byte is only sent once the echo
+    /// of the previous one has been received, and an echo which differs
+    /// from what was sent is reported to the `TxVerifyClient`; a wrong baud
+    /// rate or bad wiring shows up right away instead of as garbage on the
+    /// other end. Waiting for every echo roughly halves the throughput, and
+    /// a transmit never completes if the echoes don't arrive at all, in which
+    /// case `recover` aborts it.
+    ///
+    /// Echoes are consumed by the verification and never reach an active
+    /// receive, so nothing else should be sent on the line meanwhile. Bytes
+    /// already waiting in the RX FIFO when a verified transmit starts go to
+    /// the active receive, or are discarded if there is none. The setting
+    /// applies from the next transmit on. Null transmits are not verified.
+    pub fn enable_tx_verify(&self, verify: bool) {
+        self.tx_verify.set(verify);
+    }
+
+    /// Set the client for mismatches found by `enable_tx_verify`.
+    pub fn set_tx_verify_client(&self, client: &'a dyn TxVerifyClient) {
+        self.tx_verify_client.set(client);
+    }
+
+    /// Append `delimiter` to every transmit started with `transmit_buffer`
+    /// (or `transmit_buffer_mode`), so the receiver can split the byte
+    /// stream into packets, e.g. `Some(0)` after COBS-encoded frames or
+    /// `Some(b'\n')` for lines. The completion callback still reports the
+    /// length of the caller's buffer, without the delimiter. `None`, the
+    /// default, sends buffers as they are. `transmit_const` and
+    /// `transmit_stream` are not affected.
+    pub fn set_frame_delimiter(&self, delimiter: Option<u8>) {
+        self.tx_frame_delimiter.set(delimiter);
+    }
+
+    /// Limit transmits to `bytes_per_sec` on average, e.g. when the other
+    /// end has little buffering. 0, the default, means unlimited.
+    ///
+    /// The limit is a token bucket holding up to `TX_RATE_LIMIT_BURST`
+    /// bytes, refilled at `bytes_per_sec` using the time source. The bucket
+    /// starts full, so a burst of up to that many bytes after an idle period
+    /// goes out at the line rate; after that, every FIFO refill is limited to
+    /// the bytes the bucket holds. When it is empty the TX interrupt is
+    /// disabled and the alarm of the time source is armed for the next
+    /// token, so transmits still complete, only slower. Without a
+    /// `TimeSource` transmits are not limited.
+    pub fn set_tx_rate_limit(&self, bytes_per_sec: u32) {
+        self.tx_rate_limit.set(bytes_per_sec);
+        self.tx_tokens.set(TX_RATE_LIMIT_BURST);
+        self.time_source
+            .map(|time| self.tx_tokens_updated.set(time.now()));
+    }
+
+    /// Top up the rate limit bucket for the time passed since the last
+    /// update and return how many bytes may be written now, or `None` if
+    /// transmits are not limited.
+    fn tx_allowance(&self) -> Option<u32> {
+        let rate = self.tx_rate_limit.get() as u64;
+        if rate == 0 {
+            return None;
+        }
+        self.time_source.map(|time| {
+            let now = time.now();
+            let freq = time.frequency() as u64;
+            let elapsed = now.wrapping_sub(self.tx_tokens_updated.get());
+            let new_tokens = elapsed * rate / freq;
+            let tokens = self.tx_tokens.get() as u64 + new_tokens;
+            if tokens >= TX_RATE_LIMIT_BURST as u64 {
+                self.tx_tokens.set(TX_RATE_LIMIT_BURST);
+                self.tx_tokens_updated.set(now);
+            } else {
+                // Keep the fraction of a token earned so far.
+                self.tx_tokens.set(tokens as u32);
+                self.tx_tokens_updated.set(
+                    self.tx_tokens_updated
+                        .get()
+                        .wrapping_add(new_tokens * freq / rate),
+                );
+            }
+            self.tx_tokens.get()
+        })
+    }
+
+    /// Pause the active transmit until the rate limit bucket has a token
+    /// again.
+    fn throttle_transmit(&self) {
+        self.disable_tx_interrupt();
+        self.time_source.map(|time| {
+            let rate = self.tx_rate_limit.get() as u64;
+            let token = (time.frequency() as u64 + rate - 1) / rate;
+            self.tx_resume_deadline
+                .set(self.tx_tokens_updated.get().wrapping_add(token));
+        });
+        self.arm_alarm();
+    }
+
+    /// Continue a transmit paused by `throttle_transmit`.
+    fn resume_transmit(&self) {
+        if !self.tx_in_progress() {
+            return;
+        }
+        if !self.tx_polled.get() && !self.tx_verify_active.get() {
+            self.enable_tx_interrupt();
+        }
+        self.fill_tx_fifo();
+    }
+
+    /// Arm the alarm of the time source for the earliest pending deadline.
+    fn arm_alarm(&self) {
+        self.time_source.map(|time| {
+            let now = time.now();
+            let until = |deadline: u64| deadline.wrapping_sub(now) as i64;
+            let next = [
+                self.tx_resume_deadline.extract(),
+                self.tx_restore_deadline.extract(),
+                self.rtu_gap_deadline.extract(),
+                self.txn_deadline.extract(),
+            ]
+            .iter()
+            .flatten()
+            .map(|deadline| until(*deadline))
+            .min();
+            next.map(|dt| time.arm(core::cmp::max(dt, 0) as u64));
+        });
+    }
+
+    /// Check whether a new transmit can be started.
+    fn transmit_ready(&self) -> Result<(), ErrorCode> {
+        if self.tx_in_progress() {
+            Err(ErrorCode::BUSY)
+        } else if self.tx_null.get() && self.deferred_handle.is_none() {
+            Err(ErrorCode::OFF)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Transmit a stream of data which does not fit in one buffer, pulling
+    /// the chunks from `source` one after another.
+    ///
+    /// Each chunk is sent like a `transmit_buffer`; once it is done it is
+    /// returned through `ChunkSource::chunk_transmitted` and the next one is
+    /// requested. When `next_chunk` returns `None` the stream completes with
+    /// `ChunkSource::stream_complete(total, Ok(()))`. If a chunk fails (e.g.
+    /// because of `recover`), that chunk is handed back, no further chunks
+    /// are requested and `stream_complete` reports the bytes transmitted so
+    /// far along with the error. The `TransmitClient` is not called for
+    /// streams.
+    ///
+    /// Returns `SIZE` without any callback if `next_chunk` returns `None`
+    /// right away. An empty first chunk starts the stream like any other and
+    /// is handed back from the TX interrupt.
+    pub fn transmit_stream(&self, source: &'a dyn ChunkSource) -> Result<(), ErrorCode> {
+        self.transmit_ready()?;
+
+        let chunk = source.next_chunk().ok_or(ErrorCode::SIZE)?;
+        self.tx_stream_sent.set(0);
+        self.tx_stream.set(source);
+        self.start_chunk(chunk);
+        Ok(())
+    }
+
+    /// Start transmitting `chunk` of the active stream.
+    fn start_chunk(&self, chunk: &'static mut [u8]) {
+        let empty = chunk.is_empty();
+        self.len.set(chunk.len());
+        self.index.set(0);
+        self.buffer.replace(chunk);
+        if empty && !self.tx_null.get() {
+            // Nothing to write, the TX interrupt completes the chunk as soon
+            // as the FIFO is empty.
+            self.tx_fifo_bytes.set(0);
+            self.enable_tx_interrupt();
+            self.enable_transmitter();
+        } else {
+            self.start_transmit();
+        }
+    }
+
+    /// Start transmitting the next non-empty chunk of `source`, returning
+    /// `false` if it is exhausted. This runs when the previous chunk has
+    /// completed, so empty chunks are handed back right away.
+    fn start_next_chunk(&self, source: &dyn ChunkSource) -> bool {
+        while let Some(chunk) = source.next_chunk() {
+            if chunk.is_empty() {
+                source.chunk_transmitted(chunk);
+                continue;
+            }
+
+            self.start_chunk(chunk);
+            return true;
+        }
+        false
+    }
+
+    /// Finish the current chunk of a stream, continuing with the next one
+    /// unless it failed or the stream is exhausted.
+    fn complete_chunk(&self, source: &'a dyn ChunkSource, rval: Result<(), ErrorCode>) {
+        self.tx_stream_sent
+            .set(self.tx_stream_sent.get() + self.index.get());
+        if let Some(chunk) = self.buffer.take() {
+            source.chunk_transmitted(chunk);
+        }
+
+        if rval.is_ok() && self.start_next_chunk(source) {
+            return;
+        }
+        self.tx_stream.clear();
+        source.stream_complete(self.tx_stream_sent.get(), rval);
+    }
+
+    /// Whether a `transmit_buffer`, `transmit_const` or `transmit_stream`
+    /// is outstanding.
+    fn tx_in_progress(&self) -> bool {
+        self.buffer.is_some() || self.tx_const.is_some() || self.tx_stream.is_some()
+    }
+
+    /// Write bytes of the active transmit to the FIFO until either all of
+    /// them are written or the FIFO is full.
+    fn fill_tx_fifo(&self) {
+        let regs = self.registers;
+
+        if self.tx_resume_deadline.is_some() {
+            // Paused by the rate limit, the alarm resumes the transmit.
+            return;
+        }
+        let allowance = self.tx_allowance();
+        if allowance == Some(0) {
+            self.throttle_transmit();
+            return;
+        }
+
+        // Stop at the end of the current chunk, and at the rate limit.
+        let start = self.index.get();
+        let mut end = core::cmp::min(self.tx_chunk_end.get(), self.len.get());
+        if let Some(allowance) = allowance {
+            end = core::cmp::min(end, start + allowance as usize);
+        }
+        let fill = |data: &[u8]| {
+            for i in start..end {
+                // Write the byte from the array to the tx register.
+                self.write_tx_byte(data[i]);
+                self.index.set(i + 1);
+                if self.tx_verify_active.get() {
+                    // Wait for the echo before sending anything else.
+                    self.tx_verify_pending.set((i, data[i]));
+                    break;
+                }
+                // Check if the buffer is full
+                if regs.txdata.is_set(txdata::full) {
+                    // If it is, break and wait for the TX interrupt.
+                    break;
+                }
+            }
+        };
+
+        match self.tx_const.extract() {
+            Some(data) => fill(data),
+            None => {
+                self.buffer.map(|buffer| fill(buffer));
+            }
+        }
+        let mut written = self.index.get() - start;
+
+        // The delimiter follows the last byte, in this refill if the FIFO
+        // still has room, otherwise in the next one.
+        if self.tx_delimiter_pending.get()
+            && self.index.get() == self.len.get()
+            && allowance.map_or(true, |allowance| written < allowance as usize)
+            && self.tx_verify_pending.is_none()
+            && !regs.txdata.is_set(txdata::full)
+        {
+            if let Some(delimiter) = self.tx_frame_delimiter.get() {
+                self.write_tx_byte(delimiter);
+                if self.tx_verify_active.get() {
+                    self.tx_verify_pending.set((self.len.get(), delimiter));
+                }
+                written += 1;
+            }
+            self.tx_delimiter_pending.set(false);
+        }
+        self.tx_fifo_bytes.set(self.tx_fifo_bytes.get() + written);
+        if allowance.is_some() {
+            self.tx_tokens.set(self.tx_tokens.get() - written as u32);
+        }
+    }
+
+    /// Start sending the transmit which was just set up.
+    fn start_transmit(&self) {
+        let regs = self.registers;
+
+        self.tx_strobed.set(false);
+
+        if self.tx_null.get() {
+            // Pretend everything went out at once.
+            self.tx_polled.set(false);
+            self.tx_delimiter_pending.set(false);
+            self.index.set(self.len.get());
+            self.tx_deferred_complete.set(true);
+            self.schedule_deferred_call();
+            return;
+        }
+
+        self.tx_chunk_end.set(self.next_chunk_end(0));
+        self.tx_fifo_bytes.set(0);
+
+        self.tx_verify_active
+            .set(self.tx_verify.get() && !self.tx_polled.get());
+        if self.tx_polled.get() {
+            // `poll_transmit` takes over from here.
+            self.schedule_deferred_call();
+        } else if self.tx_verify_active.get() {
+            // Progress is driven by the echoes, so make sure the first one
+            // received really is one.
+            self.discard_stale_rx();
+            regs.rxctrl
+                .write(rxctrl::enable::SET + rxctrl::counter.val(0));
+            self.enable_rx_interrupt();
+        } else {
+            // Enable the interrupt so we know when we can keep writing.
+            self.enable_tx_interrupt();
+        }
+
+        // Fill the TX buffer until it reports full.
+        self.fill_tx_fifo();
+
+        self.enable_transmitter();
+    }
+
+    /// Enable transmissions, and wait until the FIFO is empty before getting
+    /// an interrupt.
+    fn enable_transmitter(&self) {
+        let regs = self.registers;
+
+        let stop_bits = match self.stop_bits.get() {
+            hil::uart::StopBits::One => txctrl::nstop::OneStopBit,
+            hil::uart::StopBits::Two => txctrl::nstop::TwoStopBits,
+        };
+        regs.txctrl
+            .write(txctrl::txen::SET + stop_bits + txctrl::txcnt.val(1));
+    }
+
+    /// Empty the RX FIFO before a verified transmit, passing its contents to
+    /// the active receive if there is one.
+    fn discard_stale_rx(&self) {
+        self.drain_rx_fifo();
+        if self.rx_buffer.is_none() {
+            for _ in 0..self.fifo_depth.get() {
+                if self.read_rx_fifo().is_none() {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Check the echo of the last byte of a verified transmit, then carry
+    /// on with the transmit.
+    fn service_tx_verify(&self) {
+        let received = match self.read_rx_fifo() {
+            Some(byte) => byte,
+            None => return,
+        };
+        #[cfg(feature = "diagnostics")]
+        self.diagnostics
+            .rx_bytes
+            .set(self.diagnostics.rx_bytes.get().wrapping_add(1));
+
+        if let Some((index, sent)) = self.tx_verify_pending.take() {
+            if sent != received {
+                self.tx_verify_client.map(|client| {
+                    client.on_tx_verify_error(index, sent, received);
+                });
+            }
+        }
+
+        self.service_tx();
+        if self.rx_buffer.is_none() {
+            self.release_rx();
+        }
+    }
+
+    /// Continue the active transmit once the bytes written so far have left
+    /// the FIFO, or complete it if there is nothing left to send.
+    fn service_tx(&self) {
+        let regs = self.registers;
+
+        if !self.tx_in_progress() {
+            // Only enabled to wait for room for forwarded bytes.
+            self.disable_tx_interrupt();
+            self.forward_from.map(|source| source.resume_forwarding());
+            return;
+        }
+
+        self.tx_fifo_bytes.set(0);
+        if self.len.get() == self.index.get() && !self.tx_delimiter_pending.get() {
+            // We are done.
+            regs.txctrl.write(txctrl::txen::CLEAR);
+            self.disable_tx_interrupt();
+            self.tx_polled.set(false);
+            self.tx_strobed.set(false);
+            self.tx_verify_active.set(false);
+
+            // Signal client write done
+            self.complete_transmit(Ok(()));
+            self.forward_from.map(|source| source.resume_forwarding());
+        } else {
+            if self.index.get() == self.tx_chunk_end.get() && self.index.get() < self.len.get() {
+                // A chunk has gone out, report it and move on to the
+                // next one.
+                self.tx_chunk_end.set(self.next_chunk_end(self.index.get()));
+                self.tx_progress_client.map(|client| {
+                    client.transmit_progress(self.index.get(), self.len.get());
+                });
+            }
+
+            // More to send. Fill the buffer until it is full.
+            self.fill_tx_fifo();
+        }
+    }
+
+    /// Call transmit and receive clients from a deferred call instead of
+    /// from `handle_interrupt`.
+    ///
+    /// This is for clients whose completion logic must not run in the
+    /// interrupt handling, e.g. because it calls into other HILs which
+    /// assume they are not called from there. When enabled, a completed
+    /// transmit or receive is only noted in `handle_interrupt` (or the
+    /// alarm), and the client is called, and handed back its buffer, the
+    /// next time the kernel runs the deferred call set with
+    /// `set_deferred_call`. This delays callbacks by one pass of the kernel
+    /// loop. Until then the buffer still belongs to the driver, so a new
+    /// transfer in the same direction returns `BUSY`. Needs the deferred
+    /// call; without one, clients are called directly as before. `recover`
+    /// and `prepare_for_update` still return outstanding buffers from
+    /// within the call.
+    pub fn set_deferred_callbacks(&self, deferred: bool) {
+        self.deferred_callbacks.set(deferred);
+    }
+
+    /// Whether client callbacks go through the deferred call.
+    fn defer_callbacks(&self) -> bool {
+        self.deferred_callbacks.get() && self.deferred_handle.is_some()
+    }
+
+    /// Whether a receive buffer is posted or waiting to be handed back, or
+    /// received bytes are forwarded.
+    fn rx_busy(&self) -> bool {
+        self.rx_buffer.is_some() || self.rx_deferred_buffer.is_some() || self.forward_to.is_some()
+    }
+
+    /// Hand the finished transmit back to its client, see
+    /// `set_deferred_callbacks`.
+    fn complete_transmit(&self, rval: Result<(), ErrorCode>) {
+        if self.tx_restore_baud.is_some() {
+            // The last byte is still in the shift register. Restore the baud
+            // rate from the alarm once it has left, and complete then.
+            // `transmit_at_baud` made sure there is a time source.
+            self.time_source.map(|time| {
+                let dt = time.us_to_ticks(self.frame_time_us());
+                self.tx_restore_deadline.set(time.now().wrapping_add(dt));
+            });
+            self.tx_restore_rval.set(rval);
+            self.arm_alarm();
+            return;
+        }
+        if self.defer_callbacks() {
+            self.tx_deferred_rval.set(rval);
+            self.schedule_deferred_call();
+        } else {
+            self.deliver_transmit(rval);
+        }
+    }
+
+    /// Call the clients of transfers completed with deferred callbacks.
+    fn deliver_deferred_callbacks(&self) {
+        if let Some(rval) = self.tx_deferred_rval.take() {
+            self.deliver_transmit(rval);
+        }
+        if let Some((rval, error)) = self.rx_deferred_status.take() {
+            if let Some(buffer) = self.rx_deferred_buffer.take() {
+                self.rx_buffer.replace(buffer);
+                self.deliver_receive(rval, error);
+            }
+        }
+    }
+
+    /// Call the client of the finished transmit.
+    fn deliver_transmit(&self, rval: Result<(), ErrorCode>) {
+        if self.txn.get() == Transaction::AckTransmitting && rval.is_ok() {
+            // Keep the buffer until the acknowledgment arrives.
+            self.await_ack();
+            return;
+        }
+        #[cfg(feature = "diagnostics")]
+        if rval.is_ok()
+            && (self.tx_const.is_some() || self.tx_stream.is_some() || self.buffer.is_some())
+        {
+            let completed = &self.diagnostics.completed_transmits;
+            completed.set(completed.get().wrapping_add(1));
+        }
+        if self.tx_const.take().is_some() {
+            self.tx_const_client.map(|client| {
+                client.transmitted_const(self.index.get(), rval);
+            });
+        } else if let Some(source) = self.tx_stream.extract() {
+            self.complete_chunk(source, rval);
+        } else if let Some(buffer) = self.buffer.take() {
+            if let Transaction::AckTransmitting | Transaction::AwaitingAck = self.txn.get() {
+                self.txn.set(Transaction::Idle);
+                self.txn_deadline.clear();
+            }
+            if self.txn.get() == Transaction::Transmitting {
+                self.transaction_transmitted(buffer, rval);
+            } else {
+                self.tx_client.map(|client| {
+                    client.transmitted_buffer(buffer, self.index.get(), rval);
+                });
+            }
+        }
+        #[cfg(feature = "async")]
+        self.tx_waker.map(|waker| waker.wake_by_ref());
+    }
+
+    /// Which interrupts are enabled in the `ie` register, as
+    /// `(txwm_enabled, rxwm_enabled)`.
+    pub fn interrupts_enabled(&self) -> (bool, bool) {
+        let ie = self.registers.ie.extract();
+        (ie.is_set(interrupt::txwm), ie.is_set(interrupt::rxwm))
+    }
+
+    /// Which watermark conditions are pending in the `ip` register, as
+    /// `(txwm_pending, rxwm_pending)`. These reflect the FIFO levels whether
+    /// or not the interrupt is enabled.
+    pub fn interrupts_pending(&self) -> (bool, bool) {
+        let ip = self.registers.ip.extract();
+        (ip.is_set(interrupt::txwm), ip.is_set(interrupt::rxwm))
+    }
+
+    fn enable_tx_interrupt(&self) {
+        let regs = self.registers;
+        regs.ie.modify(interrupt::txwm::SET);
+    }
+
+    fn disable_tx_interrupt(&self) {
+        let regs = self.registers;
+        regs.ie.modify(interrupt::txwm::CLEAR);
+    }
+
+    /// Transmit every byte received on this UART out of `dest`, bridging the
+    /// two without a capsule, until `stop_forwarding` is called.
+    ///
+    /// Bytes are moved from this UART's RX FIFO straight into the TX FIFO
+    /// of `dest` in the interrupt handling, there is no queue in between.
+    /// When `dest` can't take a byte, because its TX FIFO is full or it is
+    /// busy with a transmit of its own, the bytes wait in this UART's RX
+    /// FIFO and its RX interrupt is masked until `dest` has drained its FIFO
+    /// or finished that transmit. Both UARTs' interrupts must be serviced
+    /// for this. If `dest` stays slower than the incoming data, e.g.
+    /// because it runs at a lower baud rate, the RX FIFO fills up after
+    /// `FIFO_DEPTH` bytes and the hardware drops the bytes which arrive
+    /// after that; there is no flow control to slow the sender down.
+    ///
+    /// Transmits on `dest` keep working and are not interleaved with
+    /// forwarded bytes. Receives on this UART return `BUSY` while
+    /// forwarding. Returns `INVAL` if `dest` is this UART, and `BUSY` if a
+    /// receive is posted, this UART already forwards or `dest` already
+    /// receives forwarded bytes. `recover` and `prepare_for_update` stop
+    /// the forwarding.
+    pub fn forward_rx_to(&'a self, dest: &'a Uart<'a>) -> Result<(), ErrorCode> {
+        let regs = self.registers;
+
+        if core::ptr::eq(self, dest) {
+            return Err(ErrorCode::INVAL);
+        }
+        if self.rx_busy() || dest.forward_from.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+
+        self.forward_to.set(dest);
+        dest.forward_from.set(self);
+
+        regs.rxctrl
+            .write(rxctrl::enable::SET + rxctrl::counter.val(0));
+        self.enable_rx_interrupt();
+
+        Ok(())
+    }
+
+    /// Stop forwarding received bytes set up with `forward_rx_to`. Bytes
+    /// still in the RX FIFO stay there for the next receive.
+    pub fn stop_forwarding(&self) {
+        if let Some(dest) = self.forward_to.take() {
+            dest.forward_from.clear();
+            if !dest.tx_in_progress() {
+                dest.disable_tx_interrupt();
+            }
+            self.release_rx();
+        }
+    }
+
+    /// Move bytes from the RX FIFO to the destination of `forward_rx_to`.
+    fn forward_rx(&self, dest: &Uart) {
+        for _ in 0..self.fifo_depth.get() {
+            if !dest.forward_room() {
+                // Wait for `dest` to call `resume_forwarding`.
+                self.disable_rx_interrupt();
+                dest.enable_tx_interrupt();
+                return;
+            }
+            match self.read_rx_fifo() {
+                Some(byte) => dest.forward_byte(byte),
+                None => return,
+            }
+        }
+    }
+
+    /// Continue forwarding after the destination has made room.
+    fn resume_forwarding(&self) {
+        if self.forward_to.is_some() {
+            self.enable_rx_interrupt();
+        }
+    }
+
+    /// Whether a forwarded byte can be written to the TX FIFO right now.
+    fn forward_room(&self) -> bool {
+        let regs = self.registers;
+        !self.tx_in_progress() && !regs.txdata.is_set(txdata::full)
+    }
+
+    /// Transmit a byte forwarded from another UART.
+    fn forward_byte(&self, byte: u8) {
+        let regs = self.registers;
+
+        // The transmitter is turned off after each transmit.
+        if !regs.txctrl.is_set(txctrl::txen) {
+            self.enable_transmitter();
+        }
+        self.write_tx_byte(byte);
+    }
+
+    fn enable_rx_interrupt(&self) {
+        let regs = self.registers;
+        regs.ie.modify(interrupt::rxwm::SET);
+    }
+
+    fn disable_rx_interrupt(&self) {
+        let regs = self.registers;
+        regs.ie.modify(interrupt::rxwm::CLEAR);
+    }
+
+    /// Start receiving into `rx_buffer` in the given mode.
+    fn start_receive(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+        mode: RxMode,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        let regs = self.registers;
+
+        if rx_len == 0 || rx_len > rx_buffer.len() {
+            return Err((ErrorCode::SIZE, rx_buffer));
+        }
+        if self.rx_busy() {
+            return Err((ErrorCode::BUSY, rx_buffer));
+        }
+
+        self.rx_buffer.replace(rx_buffer);
+        self.rx_len.set(rx_len);
+        self.rx_start_mode.set(mode);
+        self.rx_retries.set(0);
+        self.restart_receive();
+        self.check_rx_overrun();
+        if self.rx_ring_len.get() > 0 {
+            // The ring goes first; deliver from there rather than from
+            // within this call.
+            self.schedule_deferred_call();
+        }
+
+        // Interrupt as soon as there is at least one byte in the FIFO.
+        regs.rxctrl
+            .write(rxctrl::enable::SET + rxctrl::counter.val(0));
+        self.enable_rx_interrupt();
+
+        Ok(())
+    }
+
+    /// With the `Overrun` policy, note whether the RX FIFO is full as the
+    /// receive is (re-)posted, in which case bytes may have been lost.
+    fn check_rx_overrun(&self) {
+        let regs = self.registers;
+
+        if self.rx_no_buffer_policy.get() == RxNoBufferPolicy::Overrun
+            && regs.rxctrl.is_set(rxctrl::enable)
+        {
+            // `rxwm` with the watermark just below the depth means full.
+            let counter = regs.rxctrl.read(rxctrl::counter);
+            regs.rxctrl
+                .modify(rxctrl::counter.val(self.fifo_depth.get() as u32 - 1));
+            self.rx_overrun.set(regs.ip.is_set(interrupt::rxwm));
+            regs.rxctrl.modify(rxctrl::counter.val(counter));
+        }
+    }
+
+    /// Start over filling the posted receive buffer from its beginning.
+    fn restart_receive(&self) {
+        self.rx_index.set(0);
+        self.rx_mode.set(self.rx_start_mode.get());
+        self.rx_prefix_matched.set(0);
+        self.rx_delimiter_matched.set(0);
+        self.rx_overflow.set(false);
+    }
+
+    /// Automatically restart receives which fail, up to `max` times, before
+    /// reporting the failure to the client. 0, the default, reports every
+    /// failure.
+    ///
+    /// This applies to failures the driver detects itself: an RTU frame or
+    /// a `receive_until_seq` overflowing its buffer, a receive which would
+    /// be reported with `hil::uart::Error::OverrunError` (see
+    /// `RxNoBufferPolicy::Overrun`), and a `transmit_then_receive` response
+    /// cut off by its timeout and reported as `hil::uart::Error::Truncated`
+    /// (see `set_reject_truncated`), which gets the full timeout again.
+    /// `receive_abort` and `recover` are never retried. A retry re-posts
+    /// the same buffer and restarts frame capture: bytes received so far
+    /// are discarded, and a prefix receive waits for the prefix again. The
+    /// count applies per call to `receive_buffer` (or the other receive
+    /// methods); once it is used up, the next failure is delivered to the
+    /// client as usual.
+    pub fn set_rx_retry(&self, max: u32) {
+        self.rx_retry_max.set(max);
+    }
+
+    /// Restart the active receive if a retry is left, returning whether it
+    /// was.
+    fn retry_receive(&self) -> bool {
+        if self.rx_retries.get() >= self.rx_retry_max.get() {
+            return false;
+        }
+        self.rx_retries.set(self.rx_retries.get() + 1);
+        self.restart_receive();
+        self.check_rx_overrun();
+        if self.txn.get() == Transaction::Receiving {
+            self.start_txn_timeout();
+        }
+        true
+    }
+
+    /// Fail the active receive, unless a retry is left.
+    fn fail_receive(&self, rval: Result<(), ErrorCode>, error: hil::uart::Error) {
+        if !self.retry_receive() {
+            self.complete_receive(rval, error);
+        }
+    }
+
+    /// Receive `rx_len` bytes into `rx_buffer`, but only after the byte
+    /// sequence `prefix` has been seen on the line.
+    ///
+    /// Bytes are discarded until the whole prefix has been matched; the
+    /// prefix itself is not stored in the buffer. When a partial match is
+    /// broken by a non-matching byte the matcher is reset, keeping whatever
+    /// tail of the bytes seen so far is itself a start of the prefix. This
+    /// means a prefix of `AA AA 55` is still found in the stream
+    /// `AA AA AA 55`.
+    ///
+    /// `prefix` is copied, so it does not need to outlive the call. It must
+    /// be between 1 and `MAX_RX_PREFIX_LEN` bytes long, otherwise `INVAL` is
+    /// returned. Completion is reported through the usual
+    /// `ReceiveClient::received_buffer` callback.
+    pub fn receive_after_prefix(
+        &self,
+        prefix: &[u8],
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if prefix.is_empty() || prefix.len() > MAX_RX_PREFIX_LEN {
+            return Err((ErrorCode::INVAL, rx_buffer));
+        }
+        if self.rx_busy() {
+            return Err((ErrorCode::BUSY, rx_buffer));
+        }
+
+        let mut stored = [0; MAX_RX_PREFIX_LEN];
+        stored[..prefix.len()].copy_from_slice(prefix);
+        self.rx_prefix.set(stored);
+        self.rx_prefix_len.set(prefix.len());
+
+        self.start_receive(rx_buffer, rx_len, RxMode::AwaitPrefix)
+    }
+
+    /// Receive into `rx_buffer` until the byte sequence `delimiter` has been
+    /// received, e.g. `b"\r\n"` for line-based protocols.
+    ///
+    /// Every byte is stored in the buffer as it arrives, and the delimiter
+    /// is matched against the end of what has been received so far. A
+    /// partial match which is then broken does not lose any bytes, they
+    /// are simply part of the data, and a delimiter which starts inside the
+    /// broken match is still found, so `\r\r\n` ends with `\r\n`. The
+    /// receive completes with `Ok(())` once the whole delimiter has been
+    /// seen. Whether the delimiter is part of the delivered length is set
+    /// with `set_keep_delimiter`; either way it is in the buffer. If
+    /// `max_len` bytes arrive without the delimiter, the receive completes
+    /// with `Err(ErrorCode::SIZE)` and the full buffer.
+    ///
+    /// `delimiter` is copied, so it does not need to outlive the call. It
+    /// must be between 1 and `MAX_RX_DELIMITER_LEN` bytes long, otherwise
+    /// `INVAL` is returned.
+    pub fn receive_until_seq(
+        &self,
+        rx_buffer: &'static mut [u8],
+        max_len: usize,
+        delimiter: &[u8],
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if delimiter.is_empty() || delimiter.len() > MAX_RX_DELIMITER_LEN {
+            return Err((ErrorCode::INVAL, rx_buffer));
+        }
+        if self.rx_busy() {
+            return Err((ErrorCode::BUSY, rx_buffer));
+        }
+
+        let mut stored = [0; MAX_RX_DELIMITER_LEN];
+        stored[..delimiter.len()].copy_from_slice(delimiter);
+        self.rx_delimiter.set(stored);
+        self.rx_delimiter_len.set(delimiter.len());
+
+        self.start_receive(rx_buffer, max_len, RxMode::UntilDelimiter)
+    }
+
+    /// Whether the length reported for a `receive_until_seq` receive
+    /// includes the delimiter, true by default. With false, the client gets
+    /// the length of the data in front of it.
+    pub fn set_keep_delimiter(&self, keep: bool) {
+        self.rx_keep_delimiter.set(keep);
+    }
+
+    /// Advance the delimiter matcher by one received byte, returning
+    /// whether the full delimiter has now been matched.
+    fn match_delimiter_byte(&self, byte: u8) -> bool {
+        let delimiter = self.rx_delimiter.get();
+        let delimiter = &delimiter[..self.rx_delimiter_len.get()];
+        let matched = advance_match(delimiter, self.rx_delimiter_matched.get(), byte);
+        if matched == delimiter.len() {
+            self.rx_delimiter_matched.set(0);
+            true
+        } else {
+            self.rx_delimiter_matched.set(matched);
+            false
+        }
+    }
+
+    /// Receive a single Modbus RTU frame of up to `max_len` bytes into
+    /// `rx_buffer`.
+    ///
+    /// RTU frames are separated by at least 3.5 character times of silence.
+    /// A character is 1 start bit, 8 data bits and the configured 1 or 2
+    /// stop bits, so the gap is
+    ///
+    /// ```text
+    /// t_3.5 = 3.5 * (9 + stop_bits) / baud_rate
+    /// ```
+    ///
+    /// except above 19200 baud, where the Modbus specification fixes it at
+    /// 1750 us. That time is converted to ticks of the `TimeSource`, rounding
+    /// up. Its alarm is re-armed for `t_3.5` every time bytes are
+    /// drained from the FIFO; when it fires without new bytes having arrived
+    /// the bytes received so far are delivered through
+    /// `ReceiveClient::received_buffer` as one frame. The client is called
+    /// once per frame and re-posts the buffer to receive the next one;
+    /// bytes arriving in between wait in the FIFO. The board must register
+    /// this UART as the client of that alarm.
+    ///
+    /// A single byte followed by silence is delivered as a 1-byte frame. If a
+    /// frame is longer than `max_len` the remaining bytes are discarded until
+    /// the gap, and the first `max_len` bytes are delivered with
+    /// `Err(ErrorCode::SIZE)`.
+    ///
+    /// Returns `NOSUPPORT` if no `TimeSource` was provided.
+    pub fn receive_rtu_frames(
+        &self,
+        rx_buffer: &'static mut [u8],
+        max_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.time_source.is_none() {
+            return Err((ErrorCode::NOSUPPORT, rx_buffer));
+        }
+        self.start_receive(rx_buffer, max_len, RxMode::RtuFrame)
+    }
+
+    /// The Modbus RTU inter-frame gap (3.5 character times) in microseconds.
+    fn rtu_gap_us(&self) -> u32 {
+        let baud_rate = self.baud_rate.get();
+        if baud_rate > 19200 {
+            1750
+        } else {
+            let bits = self.frame_bits();
+            // 3.5 * bits / baud in microseconds, rounded up.
+            (7 * bits * 1_000_000 + 2 * baud_rate - 1) / (2 * baud_rate)
+        }
+    }
+
+    /// (Re-)start the RTU inter-frame gap timer.
+    fn arm_rtu_gap_alarm(&self) {
+        let gap_us = self.rtu_gap_us();
+        self.time_source.map(|time| {
+            self.rtu_gap_deadline
+                .set(time.now().wrapping_add(time.us_to_ticks(gap_us)));
+        });
+        self.arm_alarm();
+    }
+
+    /// The RTU inter-frame gap has passed.
+    fn rtu_gap_elapsed(&self) {
+        if self.rx_mode.get() != RxMode::RtuFrame || self.rx_buffer.is_none() {
+            return;
+        }
+
+        // Bytes which arrived just before the alarm may not have been
+        // picked up by the interrupt yet; if so the frame is still going.
+        if self.drain_rx_fifo() > 0 {
+            self.arm_rtu_gap_alarm();
+        } else if self.rx_index.get() > 0 {
+            if self.rx_overflow.get() {
+                self.fail_receive(Err(ErrorCode::SIZE), hil::uart::Error::None);
+            } else {
+                self.complete_receive(Ok(()), hil::uart::Error::None);
+            }
+        }
+    }
+
+    /// Only report a framed receive with `Ok(())` if the frame is complete.
+    ///
+    /// A receive ends early when the client calls `receive_abort`, e.g.
+    /// after giving up on a peer which stopped mid-frame, or when the
+    /// timeout of a `transmit_then_receive` expires before the response has
+    /// fully arrived. Either way it is reported as `Err(ErrorCode::CANCEL)`
+    /// with the number of bytes captured so far. When this is enabled and
+    /// the frame had started (the prefix was matched, or for a plain
+    /// receive at least one byte arrived), the error is
+    /// `hil::uart::Error::Truncated` rather than `hil::uart::Error::Aborted`,
+    /// so a parser never mistakes half a frame for a whole one.
+    pub fn set_reject_truncated(&self, reject: bool) {
+        self.rx_reject_truncated.set(reject);
+    }
+
+    /// Whether the frame of the active receive has started, i.e. any bytes
+    /// have been captured into the buffer.
+    fn rx_frame_started(&self) -> bool {
+        self.rx_mode.get() != RxMode::AwaitPrefix && self.rx_index.get() > 0
+    }
+
+    /// End the active receive before its frame is complete, handing the
+    /// bytes received so far to the client.
+    /// Truncated frames are retried unless the client `aborted` the receive.
+    fn end_receive_early(&self, aborted: bool) {
+        if self.rx_reject_truncated.get() && self.rx_frame_started() {
+            if aborted {
+                self.complete_receive(Err(ErrorCode::CANCEL), hil::uart::Error::Truncated);
+            } else {
+                self.fail_receive(Err(ErrorCode::CANCEL), hil::uart::Error::Truncated);
+            }
+        } else {
+            self.complete_receive(Err(ErrorCode::CANCEL), hil::uart::Error::Aborted);
+        }
+    }
+
+    /// Advance the prefix matcher by one received byte, returning whether
+    /// the full prefix has now been matched.
+    fn match_prefix_byte(&self, byte: u8) -> bool {
+        let prefix = self.rx_prefix.get();
+        let prefix = &prefix[..self.rx_prefix_len.get()];
+        let matched = advance_match(prefix, self.rx_prefix_matched.get(), byte);
+        if matched == prefix.len() {
+            self.rx_prefix_matched.set(0);
+            true
+        } else {
+            self.rx_prefix_matched.set(matched);
+            false
+        }
+    }
+
+    /// Handle a single byte drained from the RX FIFO.
+    fn receive_byte(&self, byte: u8) {
+        match self.rx_mode.get() {
+            RxMode::AwaitPrefix => {
+                if self.match_prefix_byte(byte) {
+                    self.rx_mode.set(RxMode::Buffer);
+                }
+            }
+            RxMode::Buffer => {
+                let index = self.rx_index.get();
+                self.rx_buffer.map(|buffer| {
+                    buffer[index] = byte;
+                });
+                self.rx_index.set(index + 1);
+
+                if index + 1 == self.rx_len.get() {
+                    self.complete_receive(Ok(()), hil::uart::Error::None);
+                }
+            }
+            RxMode::RtuFrame => {
+                // The frame only ends at the inter-frame gap, discard what
+                // doesn't fit.
+                let index = self.rx_index.get();
+                if index < self.rx_len.get() {
+                    self.rx_buffer.map(|buffer| {
+                        buffer[index] = byte;
+                    });
+                    self.rx_index.set(index + 1);
+                } else {
+                    self.rx_overflow.set(true);
+                }
+            }
+            RxMode::UntilDelimiter => {
+                let index = self.rx_index.get();
+                self.rx_buffer.map(|buffer| {
+                    buffer[index] = byte;
+                });
+                self.rx_index.set(index + 1);
+
+                if self.match_delimiter_byte(byte) {
+                    if !self.rx_keep_delimiter.get() {
+                        self.rx_index.set(index + 1 - self.rx_delimiter_len.get());
+                    }
+                    self.complete_receive(Ok(()), hil::uart::Error::None);
+                } else if index + 1 == self.rx_len.get() {
+                    self.fail_receive(Err(ErrorCode::SIZE), hil::uart::Error::None);
+                }
+            }
+        }
+    }
+
+    /// Hand the receive buffer back to the client, see
+    /// `set_deferred_callbacks`.
+    fn complete_receive(&self, rval: Result<(), ErrorCode>, error: hil::uart::Error) {
+        let error = if self.rx_overrun.get() && error == hil::uart::Error::None {
+            if self.retry_receive() {
+                return;
+            }
+            hil::uart::Error::OverrunError
+        } else {
+            error
+        };
+        self.rx_overrun.set(false);
+        self.release_rx();
+        if self.defer_callbacks() {
+            if let Some(buffer) = self.rx_buffer.take() {
+                self.rx_deferred_buffer.replace(buffer);
+                self.rx_deferred_status.set((rval, error));
+                self.schedule_deferred_call();
+            }
+        } else {
+            self.deliver_receive(rval, error);
+        }
+    }
+
+    /// Call the client of the finished receive.
+    fn deliver_receive(&self, rval: Result<(), ErrorCode>, error: hil::uart::Error) {
+        #[cfg(feature = "diagnostics")]
+        if rval.is_ok() && self.rx_buffer.is_some() {
+            let completed = &self.diagnostics.completed_receives;
+            completed.set(completed.get().wrapping_add(1));
+        }
+        if self.txn.get() == Transaction::Receiving {
+            if let Some(buffer) = self.rx_buffer.take() {
+                self.transaction_received(buffer, rval, error);
+            }
+            return;
+        }
+        self.rx_client.map(|client| {
+            self.rx_buffer.take().map(|buffer| {
+                client.received_buffer(buffer, self.rx_index.get(), rval, error);
+            });
+        });
+    }
+
+    /// Read the next byte from the RX FIFO hardware, or from the host
+    /// stream if there is one.
+    fn pop_rx_byte(&self) -> Option<u8> {
+        let regs = self.registers;
+
+        #[cfg(any(test, feature = "std"))]
+        if let Some(host) = self.host.extract() {
+            return host.pop_rx();
+        }
+
+        let rxdata = regs.rxdata.extract();
+        if rxdata.is_set(rxdata::empty) {
+            None
+        } else {
+            Some(rxdata.read(rxdata::data) as u8)
+        }
+    }
+
+    /// Pop one byte from the RX FIFO, or `None` if it is empty.
+    fn read_rx_fifo(&self) -> Option<u8> {
+        let byte = self.pop_rx_byte()?;
+
+        self.rx_capture.map(|capture| {
+            let len = self.rx_capture_len.get();
+            if len < capture.len() {
+                let now = self.time_source.map_or(0, |time| time.now());
+                let delta = now.wrapping_sub(self.rx_capture_last.get());
+                capture[len] = (core::cmp::min(delta, u32::MAX as u64) as u32, byte);
+                self.rx_capture_len.set(len + 1);
+                self.rx_capture_last.set(now);
+            }
+        });
+
+        if self.rx_gap_measure.get() {
+            self.time_source.map(|time| {
+                let now = time.now();
+                if let Some(last) = self.rx_last_arrival.extract() {
+                    self.rx_last_gap.set(now.wrapping_sub(last));
+                }
+                self.rx_last_arrival.set(now);
+            });
+        }
+
+        #[cfg(feature = "diagnostics")]
+        self.diagnostics.rx_tap.map(|tap| {
+            let written = self.diagnostics.rx_tap_written.get();
+            tap[written % tap.len()] = byte;
+            self.diagnostics.rx_tap_written.set(written.wrapping_add(1));
+        });
+
+        Some(byte)
+    }
+
+    /// Record the timing of every received byte into `buf`, for analysing
+    /// an unknown protocol.
+    ///
+    /// Each entry is `(delta, byte)`: `delta` is the time since the previous
+    /// byte (for the first one, since this call) in ticks of the time
+    /// source, saturating at `u32::MAX`, and 0 without a time source. Bytes
+    /// are timestamped when the driver takes them from the RX FIFO, so
+    /// bytes which arrive back to back between two interrupts show up with
+    /// tiny deltas; the gaps between bursts are what reveals frame
+    /// boundaries. Capturing sees every byte read from the FIFO, whatever
+    /// the receive mode does with it, and stops recording once `buf` is
+    /// full. Starting a new capture ends the previous one and returns its
+    /// buffer, whether it was still running or stopped but not yet taken.
+    pub fn start_timing_capture(
+        &self,
+        buf: &'static mut [(u32, u8)],
+    ) -> Option<&'static mut [(u32, u8)]> {
+        // Only one buffer is held at a time, so `stop_timing_capture` never
+        // has to displace an unclaimed one.
+        let previous = self
+            .rx_capture
+            .take()
+            .or_else(|| self.rx_capture_stopped.take());
+        self.rx_capture_len.set(0);
+        self.rx_capture_last
+            .set(self.time_source.map_or(0, |time| time.now()));
+        self.rx_capture.replace(buf);
+        previous
+    }
+
+    /// Stop the timing capture and return the number of entries recorded.
+    /// The buffer can then be retrieved with `take_timing_capture`.
+    pub fn stop_timing_capture(&self) -> usize {
+        if let Some(buf) = self.rx_capture.take() {
+            self.rx_capture_stopped.replace(buf);
+        }
+        self.rx_capture_len.get()
+    }
+
+    /// Return the buffer of a stopped timing capture. The first
+    /// `stop_timing_capture()` entries are valid.
+    pub fn take_timing_capture(&self) -> Option<&'static mut [(u32, u8)]> {
+        self.rx_capture_stopped.take()
+    }
+
+    /// Timestamp received bytes for `last_rx_interbyte_gap_us`. This is off
+    /// by default, as it reads the time source for every byte. Turning it
+    /// on or off forgets the previous measurement.
+    pub fn set_rx_gap_measurement(&self, enabled: bool) {
+        self.rx_gap_measure.set(enabled);
+        self.rx_last_arrival.clear();
+        self.rx_last_gap.clear();
+    }
+
+    /// The time between the two most recently received bytes in
+    /// microseconds, saturating at `u32::MAX`, to tell whether a peer sends
+    /// back to back or with gaps when picking idle timeouts or RTU framing
+    /// parameters. Returns `None` until two bytes have been received with
+    /// `set_rx_gap_measurement` enabled, or without a time source.
+    ///
+    /// Like `start_timing_capture`, this measures when the driver took the
+    /// bytes from the RX FIFO, not when they arrived on the wire, so it
+    /// includes interrupt latency. Bytes which queued up in the FIFO before
+    /// the interrupt was serviced show up with a gap close to 0, while the
+    /// gap to the first byte of a burst is stretched by the latency.
+    pub fn last_rx_interbyte_gap_us(&self) -> Option<u32> {
+        let gap = self.rx_last_gap.extract()?;
+        self.time_source.map(|time| {
+            let us = time.ticks_to_us(gap);
+            core::cmp::min(us, u32::MAX as u64) as u32
+        })
+    }
+
+    /// Drain the RX FIFO, passing every byte to the active receive.
+    /// Returns the number of bytes drained.
+    fn drain_rx_fifo(&self) -> usize {
+        let mut drained = 0;
+
+        // Bound the loop by the FIFO depth; anything which arrives while we
+        // are draining keeps `rxwm` asserted and is handled on the next
+        // interrupt.
+        for _ in 0..self.fifo_depth.get() {
+            if self.rx_buffer.is_none() {
+                // The receive finished, leave the remaining bytes for the
+                // next `receive_buffer` call.
+                break;
+            }
+            let byte = match self.read_rx_fifo() {
+                Some(byte) => byte,
+                None => break,
+            };
+            self.receive_byte(byte);
+            drained += 1;
+        }
+
+        #[cfg(feature = "diagnostics")]
+        {
+            let diagnostics = &self.diagnostics;
+            diagnostics
+                .rx_bytes
+                .set(diagnostics.rx_bytes.get().wrapping_add(drained as u32));
+            if drained > diagnostics.peak_rx_backlog.get() {
+                diagnostics.peak_rx_backlog.set(drained);
+            }
+        }
+
+        drained
+    }
+
+    /// Service the RX FIFO watermark interrupt.
+    fn service_rx(&self) {
+        if self.txn.get() == Transaction::AwaitingAck {
+            self.service_ack();
+            return;
+        }
+        if let Some(dest) = self.forward_to.extract() {
+            self.forward_rx(dest);
+            return;
+        }
+        let drained = self.drain_rx_ring() + self.drain_rx_fifo();
+        self.rx_progress(drained);
+        if self.rx_buffer.is_none() {
+            self.service_unposted_rx();
+        }
+    }
+
+    /// Follow up on `drained` bytes having been passed to the active
+    /// receive.
+    fn rx_progress(&self, drained: usize) {
+        if drained > 0 && self.rx_mode.get() == RxMode::RtuFrame && self.rx_buffer.is_some() {
+            self.arm_rtu_gap_alarm();
+        }
+    }
+
+    /// Provide the ring buffer `RxNoBufferPolicy::BufferInRing` keeps bytes
+    /// in, holding up to `ring.len()` of them. Bytes kept in a previous
+    /// ring are discarded.
+    pub fn set_rx_ring(&self, ring: &'static mut [u8]) {
+        self.rx_ring_start.set(0);
+        self.rx_ring_len.set(0);
+        self.rx_ring.replace(ring);
+    }
+
+    /// Choose what happens to bytes which arrive while no receive is
+    /// posted. The default is `RxNoBufferPolicy::Drop`.
+    ///
+    /// Returns `OFF` for `BufferInRing` if no deferred call or no ring
+    /// buffer is available. Switching away from `BufferInRing` discards the
+    /// bytes in the ring.
+    pub fn set_no_buffer_policy(&self, policy: RxNoBufferPolicy) -> Result<(), ErrorCode> {
+        if policy == RxNoBufferPolicy::BufferInRing
+            && (self.deferred_handle.is_none() || self.rx_ring.is_none())
+        {
+            return Err(ErrorCode::OFF);
+        }
+        if policy != RxNoBufferPolicy::BufferInRing {
+            self.rx_ring_len.set(0);
+        }
+        self.rx_no_buffer_policy.set(policy);
+        if self.rx_buffer.is_none() {
+            self.release_rx();
+        }
+        Ok(())
+    }
+
+    /// Nothing needs the RX FIFO anymore, stop or keep servicing it as the
+    /// no-buffer policy says.
+    fn release_rx(&self) {
+        let regs = self.registers;
+
+        if self.tx_verify_pending.is_some() {
+            return;
+        }
+        if self.rx_no_buffer_policy.get() == RxNoBufferPolicy::Overrun
+            || !regs.rxctrl.is_set(rxctrl::enable)
+        {
+            self.disable_rx_interrupt();
+        } else {
+            self.enable_rx_interrupt();
+        }
+    }
+
+    /// Deal with received bytes while no receive is posted.
+    fn service_unposted_rx(&self) {
+        if self.txn.get() != Transaction::Idle
+            || self.rx_no_buffer_policy.get() == RxNoBufferPolicy::Overrun
+        {
+            // A response is about to be received, or the bytes wait for
+            // the next receive.
+            self.disable_rx_interrupt();
+            return;
+        }
+        for _ in 0..self.fifo_depth.get() {
+            let byte = match self.read_rx_fifo() {
+                Some(byte) => byte,
+                None => break,
+            };
+            if self.rx_no_buffer_policy.get() == RxNoBufferPolicy::BufferInRing {
+                let len = self.rx_ring_len.get();
+                self.rx_ring.map(|ring| {
+                    if len < ring.len() {
+                        ring[(self.rx_ring_start.get() + len) % ring.len()] = byte;
+                        self.rx_ring_len.set(len + 1);
+                    }
+                });
+            }
+        }
+    }
+
+    /// Pass bytes kept by `RxNoBufferPolicy::BufferInRing` to the active
+    /// receive. Returns the number of bytes passed.
+    fn drain_rx_ring(&self) -> usize {
+        let mut drained = 0;
+        while self.rx_ring_len.get() > 0 && self.rx_buffer.is_some() {
+            let start = self.rx_ring_start.get();
+            let byte = match self.rx_ring.map(|ring| {
+                self.rx_ring_start.set((start + 1) % ring.len());
+                ring[start]
+            }) {
+                Some(byte) => byte,
+                None => break,
+            };
+            self.rx_ring_len.set(self.rx_ring_len.get() - 1);
+            self.receive_byte(byte);
+            drained += 1;
+        }
+        drained
+    }
+
+    /// Service the UART interrupt.
+    ///
+    /// Both causes are handled from one snapshot of `ip`, RX first so the
+    /// FIFO is drained before a TX completion callback can start something
+    /// new. Servicing can make a cause (re-)assert, e.g. when a client posts
+    /// a receive from its transmit callback while bytes are waiting, so `ip`
+    /// is then read again and serviced until no enabled cause is left, for
+    /// at most `MAX_INTERRUPT_PASSES` passes. Anything still pending after
+    /// that keeps the level-triggered interrupt asserted and is handled on
+    /// the next call.
+    pub fn handle_interrupt(&self) {
+        let regs = self.registers;
+
+        #[cfg(feature = "diagnostics")]
+        {
+            if regs.ip.get() & regs.ie.get() == 0 {
+                self.diagnostics
+                    .spurious_interrupts
+                    .set(self.diagnostics.spurious_interrupts.get().wrapping_add(1));
+            }
+            if self.diagnostics.woken.take() {
+                self.diagnostics
+                    .wakeups
+                    .set(self.diagnostics.wakeups.get().wrapping_add(1));
+            }
+        }
+
+        for _ in 0..MAX_INTERRUPT_PASSES {
+            // Get a copy so we can check each interrupt flag in the register.
+            // `ip` reflects the FIFO watermark conditions regardless of `ie`,
+            // so only look at the sources we actually enabled.
+            let pending_interrupts = regs.ip.extract();
+            let enabled_interrupts = regs.ie.extract();
+
+            if pending_interrupts.get() & enabled_interrupts.get() == 0 {
+                break;
+            }
+
+            if pending_interrupts.is_set(interrupt::rxwm)
+                && enabled_interrupts.is_set(interrupt::rxwm)
+            {
+                if self.tx_verify_pending.is_some() {
+                    self.service_tx_verify();
+                }
+                self.service_rx();
+            }
+
+            // Determine why an interrupt occurred.
+            if pending_interrupts.is_set(interrupt::txwm)
+                && enabled_interrupts.is_set(interrupt::txwm)
+            {
+                // Got a TX interrupt which means the number of bytes in the
+                // FIFO has fallen to zero. If there is more to send do that,
+                // otherwise send a callback to the client.
+                self.service_tx();
+            }
+        }
+    }
+
+    /// Check whether `configure` would accept `params`, without touching
+    /// the hardware.
+    ///
+    /// Returns the same error `configure` would:
+    /// - NOSUPPORT: parity, hardware flow control or a word width other
+    ///   than 8 bits was requested. This UART supports none of these. Both
+    ///   one and two stop bits are supported.
+    /// - INVAL: the baud rate can't be derived from the clock, because it is
+    ///   zero, faster than the clock or too slow for the 16-bit divisor.
+    pub fn can_configure(&self, params: &hil::uart::Parameters) -> Result<(), ErrorCode> {
+        // This chip does not support these features.
+        if params.parity != hil::uart::Parity::None {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+        if params.hw_flow_control != false {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+        if params.width != hil::uart::Width::Eight {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+
+        baud::divisor(self.uart_clock(), params.baud_rate)
+            .map(|_| ())
+            .ok_or(ErrorCode::INVAL)
+    }
+
+    /// Read the registers back and check them against what the driver last
+    /// programmed, to detect corruption, e.g. after a brownout, which would
+    /// otherwise only show up as garbage on the line.
+    ///
+    /// The driver keeps the divisor it wrote and the stop bits from
+    /// `configure`. The interrupt enables and the transmit and receive
+    /// controls change with every transfer, so those are checked against the
+    /// state of the driver instead:
+    /// - `div` must hold the divisor last written.
+    /// - While a transmit is in progress with the transmitter enabled,
+    ///   `txctrl` must have the configured number of stop bits.
+    /// - While a receive is posted, the receiver must be enabled with a
+    ///   watermark of 0 and the RX interrupt enabled.
+    /// - `ie` may only enable the TX interrupt while a transmit is in
+    ///   progress and the RX interrupt while a receive or loopback check is,
+    ///   and nothing else.
+    ///
+    /// Returns `FAIL` on any mismatch, so a supervisor can call `recover`.
+    pub fn validate_config(&self) -> Result<(), ErrorCode> {
+        let regs = self.registers;
+
+        let div_ok = self
+            .expected_div
+            .extract()
+            .map_or(true, |expected| regs.div.read(div::div) == expected as u32);
+
+        let txctrl = regs.txctrl.extract();
+        let stop_bits = match self.stop_bits.get() {
+            hil::uart::StopBits::One => 0,
+            hil::uart::StopBits::Two => 1,
+        };
+        let txctrl_ok = !(self.tx_in_progress() && txctrl.is_set(txctrl::txen))
+            || txctrl.read(txctrl::nstop) == stop_bits;
+
+        let rxctrl = regs.rxctrl.extract();
+        let ie = regs.ie.extract();
+        let rxctrl_ok = self.rx_buffer.is_none()
+            || (rxctrl.is_set(rxctrl::enable)
+                && rxctrl.read(rxctrl::counter) == 0
+                && ie.is_set(interrupt::rxwm));
+
+        let ie_ok = ie.get() & !(interrupt::txwm::SET + interrupt::rxwm::SET).value == 0
+            && (!ie.is_set(interrupt::txwm)
+                || self.tx_in_progress()
+                || self.forward_from.is_some())
+            && (!ie.is_set(interrupt::rxwm)
+                || self.rx_buffer.is_some()
+                || self.tx_verify_pending.is_some()
+                || self.forward_to.is_some()
+                || self.txn.get() == Transaction::AwaitingAck
+                || (self.rx_no_buffer_policy.get() != RxNoBufferPolicy::Overrun
+                    && rxctrl.is_set(rxctrl::enable)));
+
+        if div_ok && txctrl_ok && rxctrl_ok && ie_ok {
+            Ok(())
+        } else {
+            Err(ErrorCode::FAIL)
+        }
+    }
+
+    /// Try to bring a hung UART back into a known state without resetting
+    /// the chip.
+    ///
+    /// This disables the transmitter, the receiver and all interrupts,
+    /// drains the RX FIFO, re-applies the last configuration set with
+    /// `configure` and then reads the control registers back. Any buffer of
+    /// an outstanding transmit or receive is returned to its client with
+    /// `Err(ErrorCode::FAIL)` before this function returns, so the clients
+    /// can start over.
+    ///
+    /// This recovers from lost driver state (e.g. a transfer which never
+    /// completes because an interrupt was missed), stale interrupt
+    /// conditions, RX overruns and a corrupted divisor. It cannot recover
+    /// from a peripheral which ignores register writes, which is detected by
+    /// the read-back and reported as `Err(ErrorCode::FAIL)`, nor from a TX
+    /// FIFO which keeps reporting full after the transmitter is enabled
+    /// again: the TX FIFO can't be flushed by software, so only a chip reset
+    /// helps in that case.
+    pub fn recover(&self) -> Result<(), ErrorCode> {
+        let regs = self.registers;
+
+        self.stop_forwarding();
+        if let Some(baud) = self.tx_restore_baud.take() {
+            // Reconfigured below.
+            self.baud_rate.set(baud);
+        }
+        self.tx_restore_deadline.clear();
+        self.tx_restore_rval.clear();
+
+        regs.txctrl.write(txctrl::txen::CLEAR);
+        regs.rxctrl.write(rxctrl::enable::CLEAR);
+        regs.ie.set(0);
+
+        for _ in 0..self.fifo_depth.get() {
+            if self.read_rx_fifo().is_none() {
+                break;
+            }
+        }
+
+        let mut result = Ok(());
+        if self.configured.get() {
+            result = self.set_baud_rate(self.baud_rate.get());
+        }
+
+        // Check that the peripheral took our writes.
+        let expected_div = baud::divisor(self.uart_clock(), self.baud_rate.get());
+        if regs.ie.get() != 0
+            || regs.txctrl.is_set(txctrl::txen)
+            || regs.rxctrl.is_set(rxctrl::enable)
+            || (self.configured.get()
+                && expected_div.map(|div| div as u32) != Some(regs.div.read(div::div)))
+        {
+            result = Err(ErrorCode::FAIL);
+        }
+
+        self.rx_mode.set(RxMode::Buffer);
+        self.rx_ring_len.set(0);
+        self.rx_overrun.set(false);
+        self.tx_deferred_complete.set(false);
+        self.tx_polled.set(false);
+        self.tx_strobed.set(false);
+        self.tx_delimiter_pending.set(false);
+        self.tx_resume_deadline.clear();
+        self.tx_verify_active.set(false);
+        self.tx_verify_pending.clear();
+        self.deliver_deferred_callbacks();
+        if self.tx_in_progress() {
+            self.deliver_transmit(Err(ErrorCode::FAIL));
+        }
+        if self.rx_buffer.is_some() {
+            self.deliver_receive(Err(ErrorCode::FAIL), hil::uart::Error::ResetError);
+        }
+
+        result
+    }
+
+    /// Resolve interrupt conditions left over from before a warm boot, so
+    /// that enabling an interrupt doesn't fire it straight away.
+    ///
+    /// `ip` can't be cleared by writing it: `txwm` and `rxwm` are pending as
+    /// long as the FIFO levels cross their watermarks. Clearing them
+    /// therefore means resolving the FIFO conditions. If the transmitter is
+    /// enabled this waits for the TX FIFO to empty, then sets the TX
+    /// watermark to 0 so `txwm` is no longer pending (transmits set it again
+    /// when they need it). It then discards what is in the RX FIFO, so
+    /// `rxwm` clears. Call this during initialization, before starting any
+    /// transfer.
+    ///
+    /// Returns `FAIL` if the TX FIFO does not empty within the time a full
+    /// FIFO takes to drain. The rest of the cleanup is still done, but `txwm`
+    /// may fire once its interrupt is enabled.
+    pub fn clear_pending_interrupts(&self) -> Result<(), ErrorCode> {
+        let regs = self.registers;
+
+        let result = if regs.txctrl.is_set(txctrl::txen) {
+            regs.txctrl.modify(txctrl::txcnt.val(1));
+            self.wait_tx_fifo_empty()
+        } else {
+            Ok(())
+        };
+        regs.txctrl.modify(txctrl::txcnt.val(0));
+
+        for _ in 0..self.fifo_depth.get() {
+            if self.read_rx_fifo().is_none() {
+                break;
+            }
+        }
+
+        result
+    }
+
+    /// Bring the UART into a silent state before the chip is reflashed, so
+    /// that it neither raises interrupts nor drives the line while the
+    /// flash routine runs.
+    ///
+    /// In order, this:
+    /// 1. disables all UART interrupts, so the driver stops refilling the TX
+    ///    FIFO,
+    /// 2. blocks until the bytes already in the TX FIFO have been sent, plus
+    ///    one frame time for the last byte to leave the shift register if a
+    ///    time source is available,
+    /// 3. disables the transmitter and receiver and discards the RX FIFO,
+    /// 4. returns any outstanding transmit and receive to its client with
+    ///    `CANCEL` (receives with `Error::Aborted`), from within this call.
+    ///
+    /// Unlike `recover`, the configuration is left alone, except that the
+    /// baud rate changed by a `transmit_at_baud` is restored. Returns
+    /// `FAIL` if the TX FIFO does not empty within the time a full FIFO
+    /// takes to drain, or if the registers do not read back as quiescent.
+    /// The UART is silenced either way, but after a `FAIL` bytes may have
+    /// been cut off, so the update should not go ahead.
+    pub fn prepare_for_update(&self) -> Result<(), ErrorCode> {
+        let regs = self.registers;
+
+        self.stop_forwarding();
+
+        regs.ie.set(0);
+
+        let mut drained = Ok(());
+        if regs.txctrl.is_set(txctrl::txen) {
+            // Make `txwm` mean "FIFO empty" and wait for it.
+            regs.txctrl.modify(txctrl::txcnt.val(1));
+            drained = self.wait_tx_fifo_empty();
+            if drained.is_ok() {
+                self.delay_us(self.frame_time_us());
+            }
+        } else if self.tx_restore_deadline.is_some() {
+            // A `transmit_at_baud` has drained, but its last byte may still
+            // be going out.
+            self.delay_us(self.frame_time_us());
+        }
+
+        regs.txctrl.write(txctrl::txen::CLEAR);
+        regs.rxctrl.write(rxctrl::enable::CLEAR);
+        for _ in 0..self.fifo_depth.get() {
+            if self.read_rx_fifo().is_none() {
+                break;
+            }
+        }
+
+        self.rx_ring_len.set(0);
+        self.rx_overrun.set(false);
+        self.tx_deferred_complete.set(false);
+        self.tx_polled.set(false);
+        self.tx_strobed.set(false);
+        self.tx_delimiter_pending.set(false);
+        self.tx_resume_deadline.clear();
+        self.tx_verify_active.set(false);
+        self.tx_verify_pending.clear();
+        self.restore_baud();
+        if let Some(rval) = self.tx_restore_rval.take() {
+            // The transmit had already completed.
+            self.deliver_transmit(rval);
+        }
+        self.deliver_deferred_callbacks();
+        if self.tx_in_progress() {
+            self.deliver_transmit(Err(ErrorCode::CANCEL));
+        }
+        if self.rx_buffer.is_some() {
+            self.deliver_receive(Err(ErrorCode::CANCEL), hil::uart::Error::Aborted);
+        }
+
+        if regs.ie.get() != 0
+            || regs.txctrl.is_set(txctrl::txen)
+            || regs.rxctrl.is_set(rxctrl::enable)
+        {
+            return Err(ErrorCode::FAIL);
+        }
+        drained
+    }
+
+    /// Check that the UART holds no transfer and is not active, e.g. in the
+    /// teardown of a test to catch state leaking out of it, or after
+    /// `prepare_for_update` or `recover`.
+    ///
+    /// Panics with a message naming the first problem found if a transmit
+    /// or receive buffer is still held (including ones waiting to be handed
+    /// back by the deferred call), an interrupt is enabled, or the
+    /// transmitter or receiver is enabled. This uses `debug_assert!`, so
+    /// the checks are compiled out of release builds.
+    pub fn assert_quiescent(&self) {
+        let regs = self.registers;
+
+        debug_assert!(
+            !self.tx_in_progress(),
+            "{}: transmit in progress",
+            self.name()
+        );
+        debug_assert!(
+            self.tx_deferred_rval.is_none() && !self.tx_deferred_complete.get(),
+            "{}: transmit completion not delivered",
+            self.name()
+        );
+        debug_assert!(!self.rx_busy(), "{}: receive buffer held", self.name());
+        debug_assert!(
+            self.txn.get() == Transaction::Idle
+                && self.txn_tx_buffer.is_none()
+                && self.txn_rx_buffer.is_none(),
+            "{}: exchange in progress",
+            self.name()
+        );
+        debug_assert!(
+            regs.ie.get() == 0,
+            "{}: interrupts enabled (ie = {:#x})",
+            self.name(),
+            regs.ie.get()
+        );
+        debug_assert!(
+            !regs.txctrl.is_set(txctrl::txen),
+            "{}: transmitter enabled",
+            self.name()
+        );
+        debug_assert!(
+            !regs.rxctrl.is_set(rxctrl::enable),
+            "{}: receiver enabled",
+            self.name()
+        );
+    }
+
+    pub fn transmit_sync(&self, bytes: &[u8]) {
+        let regs = self.registers;
+        // Make sure the UART is enabled.
+        regs.txctrl
+            .write(txctrl::txen::SET + txctrl::nstop::OneStopBit + txctrl::txcnt.val(1));
+        for b in bytes.iter() {
+            self.wait_tx_fifo_not_full();
+            self.write_tx_byte(*b);
+        }
+    }
+
+    /// Transmit a sequence recorded with `start_timing_capture`, blocking
+    /// until it has been written and reproducing its timing.
+    ///
+    /// Each byte is written to the FIFO `delta` ticks of the time source
+    /// after the previous one (the first one `delta` ticks after this call).
+    /// Deadlines are kept relative to the start, so waits which overrun
+    /// don't accumulate. Timing is only as good as the capture and the time
+    /// source: the 32 kHz CLINT resolves about 30 us, and polling loops and
+    /// the time a full FIFO takes to drain add to the gaps, so
+    /// back-to-back bytes stay back-to-back but gaps shorter than a frame
+    /// time are lost. Without a time source the bytes are sent back to
+    /// back. Like `transmit_sync`, this must not be used while a transmit
+    /// is in progress.
+    pub fn replay_sync(&self, data: &[(u32, u8)]) {
+        let start = self.time_source.map(|time| time.now());
+        let mut deadline: u64 = 0;
+
+        self.enable_transmitter();
+        for &(delta, byte) in data.iter() {
+            deadline += delta as u64;
+            if let (Some(time), Some(start)) = (self.time_source.extract(), start) {
+                while time.now().wrapping_sub(start) < deadline {}
+            }
+            self.wait_tx_fifo_not_full();
+            self.write_tx_byte(byte);
+        }
+    }
+
+    /// Write a short control sequence to the TX FIFO in one go, so that no
+    /// other transmit can end up in the middle of it.
+    ///
+    /// The whole sequence must fit in the FIFO right now: `SIZE` is returned
+    /// if it is longer than the FIFO, and `BUSY` if the FIFO doesn't have
+    /// room for all of it at the moment or another transmit is in progress,
+    /// in which case nothing is written. The UART has no FIFO level
+    /// register, so the free space is checked by moving the TX watermark to
+    /// where it is pending only if enough room is left. The bytes are then
+    /// written back to back; the driver's interrupt handling runs in the
+    /// kernel's bottom half and can't run in between, so the sequence is
+    /// contiguous on the wire. This never blocks and issues no callback.
+    pub fn transmit_atomic(&self, bytes: &[u8]) -> Result<(), ErrorCode> {
+        let regs = self.registers;
+
+        if bytes.len() > self.fifo_depth.get() {
+            return Err(ErrorCode::SIZE);
+        }
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        if self.tx_in_progress() {
+            return Err(ErrorCode::BUSY);
+        }
+
+        // `txwm` is pending while the FIFO holds fewer than `txcnt` bytes,
+        // and there is room for `n` bytes while it holds fewer than
+        // `FIFO_DEPTH + 1 - n`. A single byte only needs the FIFO not to be
+        // full, which `txcnt`, being at most 7, can't express.
+        let fits = if bytes.len() == 1 {
+            !regs.txdata.is_set(txdata::full)
+        } else {
+            regs.txctrl
+                .modify(txctrl::txcnt.val((self.fifo_depth.get() + 1 - bytes.len()) as u32));
+            regs.ip.is_set(interrupt::txwm)
+        };
+        if !fits {
+            regs.txctrl.modify(txctrl::txcnt.val(1));
+            return Err(ErrorCode::BUSY);
+        }
+
+        for &byte in bytes.iter() {
+            self.write_tx_byte(byte);
+        }
+        self.enable_transmitter();
+        Ok(())
+    }
+
+    /// Limit how long `receive_exact_sync` waits for each byte, in
+    /// microseconds. `None`, the default, waits forever. The timeout needs a
+    /// `TimeSource`; without one `receive_exact_sync` returns `NOSUPPORT`
+    /// while a timeout is set.
+    pub fn set_receive_sync_timeout_us(&self, timeout: Option<u32>) {
+        self.rx_sync_timeout_us.set(timeout);
+    }
+
+    /// Receive exactly `buf.len()` bytes by polling the RX FIFO, blocking
+    /// until they have all arrived.
+    ///
+    /// This is the counterpart of `transmit_sync` for simple
+    /// request/response tools used before the interrupt path is set up. It
+    /// returns `BUSY` if an interrupt-driven receive is active, since both
+    /// would take bytes from the same FIFO, and `FAIL` if the timeout set
+    /// with `set_receive_sync_timeout_us` expires before the next byte
+    /// arrives, leaving the bytes received so far in `buf`. Rather than
+    /// waiting forever when a timeout is set but there is no time source to
+    /// measure it, it returns `NOSUPPORT` without receiving anything. The
+    /// receiver is left enabled afterwards.
+    pub fn receive_exact_sync(&self, buf: &mut [u8]) -> Result<(), ErrorCode> {
+        let regs = self.registers;
+
+        if self.rx_buffer.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        if self.rx_sync_timeout_us.get().is_some() && self.time_source.is_none() {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+
+        regs.rxctrl
+            .write(rxctrl::enable::SET + rxctrl::counter.val(0));
+
+        let timeout = self
+            .rx_sync_timeout_us
+            .get()
+            .and_then(|us| self.time_source.map(|time| (*time, time.us_to_ticks(us))));
+
+        for byte in buf.iter_mut() {
+            let start = timeout.map(|(time, _)| time.now());
+            *byte = loop {
+                if let Some(byte) = self.read_rx_fifo() {
+                    break byte;
+                }
+                if let (Some((time, dt)), Some(start)) = (timeout, start) {
+                    if time.now().wrapping_sub(start) >= dt {
+                        return Err(ErrorCode::FAIL);
+                    }
+                }
+            };
+
+            #[cfg(feature = "diagnostics")]
+            self.diagnostics
+                .rx_bytes
+                .set(self.diagnostics.rx_bytes.get().wrapping_add(1));
+        }
+
+        Ok(())
+    }
+}
+
+impl hil::uart::Configure for Uart<'_> {
+    fn configure(&self, params: hil::uart::Parameters) -> Result<(), ErrorCode> {
+        self.can_configure(&params)?;
+
+        // We can set the baud rate.
+        self.set_baud_rate(params.baud_rate)?;
+
+        // We need to save the stop bits because it is set in the TX register.
+        self.stop_bits.set(params.stop_bits);
+        self.configured.set(true);
+
+        #[cfg(feature = "diagnostics")]
+        self.record_config_change(ConfigChange::Configure {
+            baud_rate: params.baud_rate,
+            stop_bits: params.stop_bits,
+        });
+
+        Ok(())
+    }
+}
+
+impl DynamicDeferredCallClient for Uart<'_> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        if self.tx_deferred_complete.get() {
+            self.tx_deferred_complete.set(false);
+            self.deliver_transmit(Ok(()));
+        }
+        self.deliver_deferred_callbacks();
+        let drained = self.drain_rx_ring();
+        self.rx_progress(drained);
+        if self.tx_polled.get() {
+            self.poll_transmit();
+        }
+    }
+}
+
+impl hil::time::AlarmClient for Uart<'_> {
+    fn alarm(&self) {
+        // The alarm is shared by the rate limit, the RTU gap timer and the
+        // response timeout, see which deadlines have passed.
+        let now = match self.time_source.extract() {
+            Some(time) => time.now(),
+            None => return,
+        };
+        let passed = |deadline: &OptionalCell<u64>| {
+            let passed = deadline
+                .extract()
+                .map_or(false, |deadline| now.wrapping_sub(deadline) as i64 >= 0);
+            if passed {
+                deadline.clear();
+            }
+            passed
+        };
+
+        if passed(&self.tx_resume_deadline) {
+            self.resume_transmit();
+        }
+        if passed(&self.tx_restore_deadline) {
+            self.restore_baud();
+            if let Some(rval) = self.tx_restore_rval.take() {
+                self.complete_transmit(rval);
+            }
+        }
+        if passed(&self.rtu_gap_deadline) {
+            self.rtu_gap_elapsed();
+        }
+        if passed(&self.txn_deadline) {
+            match self.txn.get() {
+                Transaction::Receiving => self.end_receive_early(false),
+                Transaction::AwaitingAck => self.finish_ack(Err(ErrorCode::FAIL)),
+                _ => {}
+            }
+        }
+        self.arm_alarm();
+    }
+}
+
+impl<'a> hil::uart::Transmit<'a> for Uart<'a> {
+    fn set_transmit_client(&self, client: &'a dyn hil::uart::TransmitClient) {
+        self.tx_client.set(client);
+    }
+
+    fn transmit_buffer(
+        &self,
+        tx_data: &'static mut [u8],
+        tx_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.transmit_buffer_mode(tx_data, tx_len, TransferMode::Interrupt)
+    }
+
+    fn transmit_abort(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::FAIL)
+    }
+
+    fn transmit_word(&self, _word: u32) -> Result<(), ErrorCode> {
+        Err(ErrorCode::FAIL)
+    }
+}
+
+impl<'a> hil::uart::Receive<'a> for Uart<'a> {
+    fn set_receive_client(&self, client: &'a dyn hil::uart::ReceiveClient) {
+        self.rx_client.set(client);
+    }
+
+    fn receive_buffer(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.start_receive(rx_buffer, rx_len, RxMode::Buffer)
+    }
+
+    fn receive_abort(&self) -> Result<(), ErrorCode> {
+        if self.rx_buffer.is_none() {
+            return Ok(());
+        }
+
+        self.end_receive_early(true);
+        Err(ErrorCode::BUSY)
+    }
+
+    fn receive_word(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::FAIL)
+    }
+}
+
+/// Advance a streaming matcher for `pattern` which has already matched
+/// `matched` bytes by one more input byte, returning the new match length.
+///
+/// On a mismatch this falls back to the longest suffix of the bytes seen so
+/// far which is also a prefix of `pattern`, so overlapping occurrences are
+/// not missed.
+fn advance_match(pattern: &[u8], matched: usize, byte: u8) -> usize {
+    if pattern[matched] == byte {
+        return matched + 1;
+    }
+
+    // The bytes seen so far are `pattern[..matched]` followed by `byte`. Try
+    // successively shorter candidates for the new match length.
+    for candidate in (1..=matched).rev() {
+        let start = matched + 1 - candidate;
+        if pattern[candidate - 1] == byte && pattern[..candidate - 1] == pattern[start..matched] {
+            return candidate;
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::host::{static_buffer, HostStream, MockRegisters};
+    use super::{
+        div, interrupt, pack_config, rxctrl, rxdata, txctrl, txdata, RxNoBufferPolicy, TimeSource,
+        Uart, MAX_DIV_WRITE_ATTEMPTS,
+    };
+    use core::cell::Cell;
+    use kernel::dynamic_deferred_call::{
+        DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+        DynamicDeferredCallClientState,
+    };
+    use kernel::hil::time::AlarmClient;
+    use kernel::hil::uart::{self, Configure, Receive, Transmit};
+    use kernel::utilities::registers::interfaces::{ReadWriteable, Readable, Writeable};
+    use kernel::utilities::StaticRef;
+    use kernel::ErrorCode;
+    use std::boxed::Box;
+    use std::vec;
+
+    /// A UART backed by plain memory instead of MMIO registers.
+    fn mock_uart() -> &'static Uart<'static> {
+        mock_uart_with_hw().0
+    }
+
+    /// A mock UART, together with its registers for tests which play the
+    /// hardware and raise interrupts.
+    fn mock_uart_with_hw() -> (&'static Uart<'static>, MockRegisters) {
+        let hw = MockRegisters::new();
+        (Box::leak(Box::new(Uart::new(hw.base(), 16_000_000))), hw)
+    }
+
+    /// Register `uart` with a fresh deferred call instance.
+    fn attach_deferred_call(
+        uart: &'static Uart<'static>,
+    ) -> (&'static DynamicDeferredCall, DeferredCallHandle) {
+        let states: &'static [DynamicDeferredCallClientState] =
+            Box::leak(Box::new([DynamicDeferredCallClientState::default()]));
+        let ddc: &'static DynamicDeferredCall =
+            Box::leak(Box::new(DynamicDeferredCall::new(states)));
+        let handle = ddc.register(uart).unwrap();
+        uart.set_deferred_call(ddc, handle);
+        (ddc, handle)
+    }
+
+    /// A clock which advances by one microsecond every time it is read.
+    /// Tests can also move it forward by setting `ticks`.
+    #[derive(Default)]
+    struct SteppingTime {
+        ticks: Cell<u64>,
+    }
+
+    impl TimeSource for SteppingTime {
+        fn now(&self) -> u64 {
+            self.ticks.set(self.ticks.get() + 1);
+            self.ticks.get()
+        }
+
+        fn frequency(&self) -> u32 {
+            1_000_000
+        }
+
+        fn arm(&self, _dt: u64) {}
+    }
+
+    fn buffer(len: usize) -> &'static mut [u8] {
+        Box::leak(vec![0; len].into_boxed_slice())
+    }
+
+    const PARAMS_115200: uart::Parameters = uart::Parameters {
+        baud_rate: 115200,
+        width: uart::Width::Eight,
+        parity: uart::Parity::None,
+        stop_bits: uart::StopBits::One,
+        hw_flow_control: false,
+    };
+
+    fn configure_115200(uart: &Uart) {
+        uart.configure(PARAMS_115200).unwrap();
+    }
+
+    /// Records the last callback of each kind.
+    #[derive(Default)]
+    struct Client {
+        tx_calls: Cell<usize>,
+        tx_len: Cell<usize>,
+        tx_rval: Cell<Option<Result<(), ErrorCode>>>,
+        rx_calls: Cell<usize>,
+        rx_len: Cell<usize>,
+        rx_rval: Cell<Option<Result<(), ErrorCode>>>,
+        rx_error: Cell<Option<uart::Error>>,
+        rx_data: core::cell::RefCell<std::vec::Vec<u8>>,
+    }
+
+    impl Client {
+        fn attach(uart: &'static Uart<'static>) -> &'static Client {
+            let client: &'static Client = Box::leak(Box::new(Client::default()));
+            uart.set_transmit_client(client);
+            uart.set_receive_client(client);
+            client
+        }
+    }
+
+    impl uart::TransmitClient for Client {
+        fn transmitted_buffer(
+            &self,
+            _tx_buffer: &'static mut [u8],
+            tx_len: usize,
+            rval: Result<(), ErrorCode>,
+        ) {
+            self.tx_calls.set(self.tx_calls.get() + 1);
+            self.tx_len.set(tx_len);
+            self.tx_rval.set(Some(rval));
+        }
+    }
+
+    impl uart::ReceiveClient for Client {
+        fn received_buffer(
+            &self,
+            rx_buffer: &'static mut [u8],
+            rx_len: usize,
+            rval: Result<(), ErrorCode>,
+            error: uart::Error,
+        ) {
+            self.rx_calls.set(self.rx_calls.get() + 1);
+            self.rx_len.set(rx_len);
+            *self.rx_data.borrow_mut() = rx_buffer[..rx_len].into();
+            self.rx_rval.set(Some(rval));
+            self.rx_error.set(Some(error));
+        }
+    }
+
+    #[test]
+    fn recover_from_stuck_transmit() {
+        let uart = mock_uart();
+        let client = Client::attach(uart);
+        let regs = uart.registers;
+        configure_115200(uart);
+
+        // The mock never raises `txwm`, so the transmit never completes.
+        assert_eq!(uart.transmit_buffer(buffer(4), 4), Ok(()));
+        assert!(uart.receive_buffer(buffer(4), 4).is_ok());
+        // A glitch leaves the FIFO reporting full and corrupts the divisor.
+        regs.txdata.write(txdata::full::SET);
+        regs.div.write(div::div.val(3));
+        assert_eq!(client.tx_calls.get(), 0);
+
+        assert_eq!(uart.recover(), Ok(()));
+
+        assert_eq!(client.tx_calls.get(), 1);
+        assert_eq!(client.tx_rval.get(), Some(Err(ErrorCode::FAIL)));
+        assert_eq!(client.rx_calls.get(), 1);
+        assert_eq!(client.rx_rval.get(), Some(Err(ErrorCode::FAIL)));
+        assert_eq!(client.rx_error.get(), Some(uart::Error::ResetError));
+        assert_eq!(regs.div.read(div::div), 138);
+        assert_eq!(regs.ie.get(), 0);
+        assert!(!regs.txctrl.is_set(txctrl::txen));
+        assert!(!regs.rxctrl.is_set(rxctrl::enable));
+        uart.assert_quiescent();
+
+        // The driver accepts new transfers afterwards.
+        assert_eq!(uart.transmit_buffer(buffer(4), 4), Ok(()));
+        assert!(uart.receive_buffer(buffer(4), 4).is_ok());
+    }
+
+    // The checks are compiled out of release builds.
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "uart0: transmit in progress")]
+    fn assert_quiescent_names_the_problem() {
+        let uart = mock_uart();
+        uart.set_name("uart0");
+        configure_115200(uart);
+        uart.assert_quiescent();
+
+        assert_eq!(uart.transmit_buffer(buffer(4), 4), Ok(()));
+        uart.assert_quiescent();
+    }
+
+    #[test]
+    fn configure_with_write_verify() {
+        let (uart, stream) = HostStream::new_uart(16_000_000);
+        let regs = uart.registers;
+        uart.set_write_verify(true);
+        configure_115200(uart);
+
+        assert_eq!(regs.div.read(div::div), 138);
+        assert_eq!(uart.baud_rate.get(), 115200);
+
+        // Dropped writes are retried until the divisor sticks.
+        let params_9600 = uart::Parameters {
+            baud_rate: 9600,
+            ..PARAMS_115200
+        };
+        stream.drop_div_writes(MAX_DIV_WRITE_ATTEMPTS - 1);
+        assert_eq!(uart.configure(params_9600), Ok(()));
+        assert_eq!(regs.div.read(div::div), 1666);
+        assert_eq!(uart.baud_rate.get(), 9600);
+
+        // Until the attempts run out.
+        stream.drop_div_writes(MAX_DIV_WRITE_ATTEMPTS);
+        assert_eq!(uart.configure(PARAMS_115200), Err(ErrorCode::FAIL));
+        assert_eq!(regs.div.read(div::div), 1666);
+        assert_eq!(uart.baud_rate.get(), 9600);
+    }
+
+    #[test]
+    fn send_reset_break() {
+        use crate::gpio::{pins, GpioPin, GpioRegisters};
+        use kernel::hil::gpio::{Configuration, Configure as _};
+
+        let uart = mock_uart();
+        configure_115200(uart);
+        let gpio_regs: &'static GpioRegisters = Box::leak(Box::new(unsafe { core::mem::zeroed() }));
+        let tx = GpioPin::new(
+            unsafe { StaticRef::new(gpio_regs as *const GpioRegisters) },
+            pins::pin17,
+            pins::pin17::SET,
+            pins::pin17::CLEAR,
+        );
+        uart.initialize_gpio_pins(&tx, &tx);
+
+        assert_eq!(uart.send_reset_break(&tx), Err(ErrorCode::NOSUPPORT));
+
+        let time: &'static SteppingTime = Box::leak(Box::default());
+        uart.set_time_source(time);
+        assert_eq!(uart.send_reset_break(&tx), Ok(()));
+        // 13 bits at 115200 baud, then one more bit as the delimiter.
+        assert!(time.ticks.get() >= 113 + 9);
+        assert!(matches!(tx.configuration(), Configuration::Function));
+
+        assert_eq!(uart.transmit_buffer(buffer(4), 4), Ok(()));
+        assert_eq!(uart.send_reset_break(&tx), Err(ErrorCode::BUSY));
+    }
+
+    #[test]
+    fn configure_packed() {
+        const DESC: u32 = pack_config(9600, uart::StopBits::Two);
+        assert_eq!(DESC, 0x13);
+
+        let uart = mock_uart();
+        let regs = uart.registers;
+        assert_eq!(uart.configure_packed(DESC), Ok(()));
+        assert_eq!(uart.baud_rate.get(), 9600);
+        assert_eq!(uart.stop_bits.get(), uart::StopBits::Two);
+        assert_eq!(regs.div.read(div::div), 1666);
+
+        assert_eq!(uart.configure_packed(0x20), Err(ErrorCode::INVAL));
+        assert_eq!(uart.configure_packed(0xf), Err(ErrorCode::INVAL));
+        assert_eq!(uart.baud_rate.get(), 9600);
+    }
+
+    #[test]
+    fn detect_fifo_depth() {
+        let (uart, hw) = mock_uart_with_hw();
+        let regs = uart.registers;
+        regs.txctrl.write(txctrl::txen::SET + txctrl::txcnt.val(2));
+
+        // Bytes still waiting in the FIFO would be miscounted.
+        assert_eq!(uart.detect_fifo_depth(), Err(ErrorCode::BUSY));
+        assert_eq!(regs.txdata.read(txdata::data), 0);
+        assert_eq!(regs.txctrl.read(txctrl::txcnt), 2);
+
+        // The mock never fills up, so the default is kept.
+        hw.set_pending(interrupt::txwm::SET.value);
+        assert_eq!(uart.detect_fifo_depth(), Ok(8));
+        assert_eq!(regs.txdata.read(txdata::data), 0xff);
+        assert!(regs.txctrl.is_set(txctrl::txen));
+        assert_eq!(regs.txctrl.read(txctrl::txcnt), 2);
+
+        regs.txdata.write(txdata::full::SET);
+        assert_eq!(uart.detect_fifo_depth(), Ok(8));
+
+        assert_eq!(uart.transmit_buffer(buffer(4), 4), Ok(()));
+        assert_eq!(uart.detect_fifo_depth(), Err(ErrorCode::BUSY));
+    }
+
+    #[test]
+    fn detect_fifo_depth_applies_depth() {
+        let (uart, stream) = HostStream::new_uart(16_000_000);
+        Client::attach(uart);
+        configure_115200(uart);
+        stream.set_tx_fifo_depth(4);
+
+        assert_eq!(uart.detect_fifo_depth(), Ok(4));
+        assert_eq!(stream.drain_tx(), [0xff; 4]);
+
+        // Sequences are limited to the smaller FIFO.
+        assert_eq!(uart.transmit_atomic(&[0; 5]), Err(ErrorCode::SIZE));
+        // One as long as the FIFO fits once it has drained, which is checked
+        // for with the watermark at `4 + 1 - 4`.
+        assert_eq!(uart.transmit_atomic(&[0]), Ok(()));
+        stream.service(uart);
+        assert_eq!(uart.transmit_atomic(&[1, 2, 3, 4]), Ok(()));
+        assert_eq!(stream.drain_tx(), [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn can_configure() {
+        let uart = mock_uart();
+        let regs = uart.registers;
+
+        assert_eq!(uart.can_configure(&PARAMS_115200), Ok(()));
+        let two_stop_bits = uart::Parameters {
+            stop_bits: uart::StopBits::Two,
+            ..PARAMS_115200
+        };
+        assert_eq!(uart.can_configure(&two_stop_bits), Ok(()));
+
+        let rejected = [
+            (
+                uart::Parameters {
+                    parity: uart::Parity::Even,
+                    ..PARAMS_115200
+                },
+                ErrorCode::NOSUPPORT,
+            ),
+            (
+                uart::Parameters {
+                    hw_flow_control: true,
+                    ..PARAMS_115200
+                },
+                ErrorCode::NOSUPPORT,
+            ),
+            (
+                uart::Parameters {
+                    width: uart::Width::Seven,
+                    ..PARAMS_115200
+                },
+                ErrorCode::NOSUPPORT,
+            ),
+            (
+                uart::Parameters {
+                    baud_rate: 0,
+                    ..PARAMS_115200
+                },
+                ErrorCode::INVAL,
+            ),
+            (
+                uart::Parameters {
+                    baud_rate: 40_000_000,
+                    ..PARAMS_115200
+                },
+                ErrorCode::INVAL,
+            ),
+            (
+                uart::Parameters {
+                    baud_rate: 200,
+                    ..PARAMS_115200
+                },
+                ErrorCode::INVAL,
+            ),
+        ];
+        for (params, error) in rejected.iter() {
+            assert_eq!(uart.can_configure(params), Err(*error));
+            assert_eq!(uart.configure(*params), Err(*error));
+        }
+
+        // None of this touched the hardware.
+        assert_eq!(regs.div.get(), 0);
+    }
+
+    #[test]
+    fn null_transmit_completes_from_deferred_call() {
+        let uart = mock_uart();
+        let client = Client::attach(uart);
+        let regs = uart.registers;
+        uart.set_null_transmit(true);
+
+        // Without a deferred call there is no way to call back later.
+        assert_eq!(
+            uart.transmit_buffer(buffer(4), 4).map_err(|(e, _)| e),
+            Err(ErrorCode::OFF)
+        );
+
+        let (ddc, handle) = attach_deferred_call(uart);
+        assert_eq!(uart.transmit_buffer(buffer(4), 4), Ok(()));
+        assert_eq!(client.tx_calls.get(), 0);
+        assert!(ddc.has_pending());
+        assert_eq!(
+            uart.transmit_buffer(buffer(4), 4).map_err(|(e, _)| e),
+            Err(ErrorCode::BUSY)
+        );
+
+        // The kernel runs the deferred call.
+        uart.call(handle);
+        assert_eq!(client.tx_calls.get(), 1);
+        assert_eq!(client.tx_len.get(), 4);
+        assert_eq!(client.tx_rval.get(), Some(Ok(())));
+
+        // The hardware was never touched.
+        assert_eq!(regs.txdata.get(), 0);
+        assert_eq!(regs.txctrl.get(), 0);
+        assert_eq!(regs.ie.get(), 0);
+    }
+
+    #[test]
+    fn transmit_rejects_bad_lengths() {
+        let uart = mock_uart();
+        let regs = uart.registers;
+        configure_115200(uart);
+
+        for (len, tx_len) in [(0, 0), (0, 5), (4, 0), (4, 5)] {
+            assert_eq!(
+                uart.transmit_buffer(buffer(len), tx_len)
+                    .map_err(|(e, b)| (e, b.len())),
+                Err((ErrorCode::SIZE, len))
+            );
+        }
+        assert_eq!(regs.txdata.get(), 0);
+        assert_eq!(regs.ie.get(), 0);
+
+        // A transmit of the whole buffer is fine.
+        assert_eq!(uart.transmit_buffer(buffer(4), 4), Ok(()));
+    }
+
+    #[test]
+    fn prepare_for_update_quiesces() {
+        let (uart, hw) = mock_uart_with_hw();
+        let client = Client::attach(uart);
+        let regs = uart.registers;
+        configure_115200(uart);
+
+        assert_eq!(uart.transmit_buffer(buffer(64), 64), Ok(()));
+        assert!(uart.receive_buffer(buffer(4), 4).is_ok());
+        assert!(regs.txctrl.is_set(txctrl::txen));
+        assert_ne!(regs.ie.get(), 0);
+
+        // The bytes in the FIFO have gone out.
+        hw.set_pending((interrupt::txwm::SET + interrupt::rxwm::SET).value);
+        assert_eq!(uart.prepare_for_update(), Ok(()));
+
+        assert_eq!(regs.ie.get(), 0);
+        assert!(!regs.txctrl.is_set(txctrl::txen));
+        assert!(!regs.rxctrl.is_set(rxctrl::enable));
+        uart.assert_quiescent();
+        assert_eq!(client.tx_calls.get(), 1);
+        assert_eq!(client.tx_rval.get(), Some(Err(ErrorCode::CANCEL)));
+        assert_eq!(client.rx_calls.get(), 1);
+        assert_eq!(client.rx_rval.get(), Some(Err(ErrorCode::CANCEL)));
+        assert_eq!(client.rx_error.get(), Some(uart::Error::Aborted));
+
+        // With the interrupts disabled, a stray interrupt changes nothing.
+        uart.handle_interrupt();
+        assert_eq!(client.tx_calls.get(), 1);
+        assert_eq!(client.rx_calls.get(), 1);
+        assert!(!regs.txctrl.is_set(txctrl::txen));
+        uart.assert_quiescent();
+
+        // A TX FIFO that never empties fails the update instead of hanging
+        // it, but still silences the UART.
+        assert_eq!(uart.transmit_buffer(buffer(64), 64), Ok(()));
+        hw.set_pending(0);
+        assert_eq!(uart.prepare_for_update(), Err(ErrorCode::FAIL));
+        assert_eq!(regs.ie.get(), 0);
+        assert!(!regs.txctrl.is_set(txctrl::txen));
+        assert_eq!(client.tx_calls.get(), 2);
+        assert_eq!(client.tx_rval.get(), Some(Err(ErrorCode::CANCEL)));
+        uart.assert_quiescent();
+    }
+
+    /// Posts a new receive whenever a transmit completes.
+    struct ReceiveAfterTransmit {
+        uart: &'static Uart<'static>,
+        tx_calls: Cell<usize>,
+        rx_calls: Cell<usize>,
+    }
+
+    impl uart::TransmitClient for ReceiveAfterTransmit {
+        fn transmitted_buffer(
+            &self,
+            _tx_buffer: &'static mut [u8],
+            _tx_len: usize,
+            rval: Result<(), ErrorCode>,
+        ) {
+            assert_eq!(rval, Ok(()));
+            self.tx_calls.set(self.tx_calls.get() + 1);
+            assert!(self.uart.receive_buffer(buffer(4), 4).is_ok());
+        }
+    }
+
+    impl uart::ReceiveClient for ReceiveAfterTransmit {
+        fn received_buffer(
+            &self,
+            _rx_buffer: &'static mut [u8],
+            rx_len: usize,
+            rval: Result<(), ErrorCode>,
+            _error: uart::Error,
+        ) {
+            assert_eq!((rx_len, rval), (4, Ok(())));
+            self.rx_calls.set(self.rx_calls.get() + 1);
+        }
+    }
+
+    #[test]
+    fn interrupt_services_both_causes_and_reasserted_ones() {
+        let (uart, hw) = mock_uart_with_hw();
+        let regs = uart.registers;
+        let client: &'static ReceiveAfterTransmit = Box::leak(Box::new(ReceiveAfterTransmit {
+            uart,
+            tx_calls: Cell::new(0),
+            rx_calls: Cell::new(0),
+        }));
+        uart.set_transmit_client(client);
+        uart.set_receive_client(client);
+        configure_115200(uart);
+        uart.set_no_buffer_policy(RxNoBufferPolicy::Overrun)
+            .unwrap();
+
+        // The mock FIFO takes all of the transmit at once.
+        assert_eq!(uart.transmit_buffer(buffer(4), 4), Ok(()));
+        assert!(uart.receive_buffer(buffer(4), 4).is_ok());
+
+        // Both the TX FIFO has emptied and RX data is waiting. Completing the
+        // transmit posts another receive while the RX data is still there,
+        // which must be picked up within the same call.
+        hw.set_pending((interrupt::txwm::SET + interrupt::rxwm::SET).value);
+        uart.handle_interrupt();
+
+        assert_eq!(client.tx_calls.get(), 1);
+        assert_eq!(client.rx_calls.get(), 2);
+        assert_eq!(regs.ie.get(), 0);
+    }
+
+    #[test]
+    fn receive_exact_sync() {
+        let uart = mock_uart();
+        let regs = uart.registers;
+        configure_115200(uart);
+
+        // The mock FIFO hands out the same byte for as long as it is not
+        // marked empty.
+        regs.rxdata.write(rxdata::data.val(0x5a));
+        let mut buf = [0; 3];
+        assert_eq!(uart.receive_exact_sync(&mut buf), Ok(()));
+        assert_eq!(buf, [0x5a; 3]);
+        assert!(regs.rxctrl.is_set(rxctrl::enable));
+
+        // Nothing arrives, so only the timeout ends the wait, which can't
+        // be measured without a time source.
+        regs.rxdata.write(rxdata::empty::SET);
+        uart.set_receive_sync_timeout_us(Some(100));
+        let mut buf = [0; 3];
+        assert_eq!(uart.receive_exact_sync(&mut buf), Err(ErrorCode::NOSUPPORT));
+        let time: &'static SteppingTime = Box::leak(Box::new(SteppingTime::default()));
+        uart.set_time_source(time);
+        assert_eq!(uart.receive_exact_sync(&mut buf), Err(ErrorCode::FAIL));
+        assert!(time.ticks.get() >= 100);
+
+        // It can't share the FIFO with an interrupt-driven receive.
+        assert!(uart.receive_buffer(buffer(4), 4).is_ok());
+        assert_eq!(uart.receive_exact_sync(&mut buf), Err(ErrorCode::BUSY));
+    }
+
+    struct FixedClock(u32);
+
+    impl super::ClockSource for FixedClock {
+        fn uart_clock_frequency(&self) -> Option<u32> {
+            Some(self.0)
+        }
+    }
+
+    #[test]
+    fn verify_clock_uses_corrected_clock() {
+        let uart = mock_uart();
+        configure_115200(uart);
+        assert_eq!(uart.verify_clock(&FixedClock(16_000_000)), Ok(()));
+
+        // Corrected for a clock running 3% fast, the divisor only gives a
+        // usable rate if the clock really is that fast.
+        assert_eq!(uart.adjust_for_drift(30_000), Ok(()));
+        assert_eq!(
+            uart.verify_clock(&FixedClock(16_000_000)),
+            Err(ErrorCode::INVAL)
+        );
+        assert_eq!(uart.verify_clock(&FixedClock(16_480_000)), Ok(()));
+    }
+
+    #[test]
+    fn baud_range() {
+        let uart = mock_uart();
+
+        let check = |(min, max): (u32, u32)| {
+            for (baud_rate, accepted) in
+                [(min, true), (max, true), (min - 1, false), (max + 1, false)]
+            {
+                let params = uart::Parameters {
+                    baud_rate,
+                    ..PARAMS_115200
+                };
+                assert_eq!(
+                    uart.can_configure(&params).is_ok(),
+                    accepted,
+                    "{}",
+                    baud_rate
+                );
+            }
+        };
+
+        assert_eq!(uart.baud_range(), (245, 16_000_000));
+        check(uart.baud_range());
+
+        // The range follows the drift-corrected clock.
+        assert_eq!(uart.adjust_for_drift(10_000), Ok(()));
+        assert_eq!(uart.baud_range(), (247, 16_160_000));
+        check(uart.baud_range());
+    }
+
+    #[test]
+    fn forward_rx_waits_for_room() {
+        let (source, source_hw) = mock_uart_with_hw();
+        let (dest, dest_hw) = mock_uart_with_hw();
+        configure_115200(source);
+        configure_115200(dest);
+
+        assert_eq!(source.forward_rx_to(source), Err(ErrorCode::INVAL));
+        assert_eq!(source.forward_rx_to(dest), Ok(()));
+        assert_eq!(
+            source.receive_buffer(buffer(4), 4).map_err(|(e, _)| e),
+            Err(ErrorCode::BUSY)
+        );
+
+        // The destination is full, so the byte stays in the source FIFO.
+        source.registers.rxdata.write(rxdata::data.val(0x42));
+        dest.registers.txdata.write(txdata::full::SET);
+        source_hw.set_pending(interrupt::rxwm::SET.value);
+        source.handle_interrupt();
+        assert_eq!(source.interrupts_enabled(), (false, false));
+        assert_eq!(dest.interrupts_enabled(), (true, false));
+        assert_eq!(source.validate_config(), Ok(()));
+        assert_eq!(dest.validate_config(), Ok(()));
+
+        // Once the destination has drained, forwarding resumes.
+        dest.registers.txdata.set(0);
+        dest_hw.set_pending(interrupt::txwm::SET.value);
+        dest.handle_interrupt();
+        assert_eq!(dest.interrupts_enabled(), (false, false));
+        assert_eq!(source.interrupts_enabled(), (false, true));
+
+        source.handle_interrupt();
+        assert_eq!(dest.registers.txdata.read(txdata::data), 0x42);
+        assert!(dest.registers.txctrl.is_set(txctrl::txen));
+
+        // With the default policy, unposted bytes keep being dropped.
+        source.stop_forwarding();
+        assert_eq!(source.interrupts_enabled(), (false, true));
+        assert!(source.receive_buffer(buffer(4), 4).is_ok());
+    }
+
+    #[test]
+    fn prefix_matcher_falls_back_to_overlap() {
+        let prefix = [0xaa, 0xaa, 0x55];
+        let mut matched = 0;
+        let mut lengths = [0; 4];
+        for (length, &byte) in lengths.iter_mut().zip(&[0xaa, 0xaa, 0xaa, 0x55]) {
+            matched = super::advance_match(&prefix, matched, byte);
+            *length = matched;
+        }
+        // The third `AA` breaks the match, but the last two still count.
+        assert_eq!(lengths, [1, 2, 2, 3]);
+
+        assert_eq!(super::advance_match(b"abac", 3, b'b'), 2);
+        assert_eq!(super::advance_match(b"abac", 3, b'x'), 0);
+    }
+
+    #[test]
+    fn receive_after_prefix() {
+        let (uart, stream) = HostStream::new_uart(16_000_000);
+        let client = Client::attach(uart);
+        configure_115200(uart);
+
+        // A prefix overlapping with itself is found after a broken match.
+        assert!(uart
+            .receive_after_prefix(&[0xaa, 0xaa, 0x55], static_buffer(2), 2)
+            .is_ok());
+        stream.push_rx(&[0xaa, 0xaa, 0xaa, 0x55, 1, 2]);
+        stream.service(uart);
+        assert_eq!(client.rx_calls.get(), 1);
+        assert_eq!(client.rx_rval.get(), Some(Ok(())));
+        assert_eq!(*client.rx_data.borrow(), [1, 2]);
+
+        // The match carries over from one interrupt to the next.
+        assert!(uart
+            .receive_after_prefix(b"$GP", static_buffer(2), 2)
+            .is_ok());
+        stream.push_rx(b"x$G");
+        stream.service(uart);
+        stream.push_rx(b"P");
+        stream.service(uart);
+        assert_eq!(client.rx_calls.get(), 1);
+        stream.push_rx(b"ab");
+        stream.service(uart);
+        assert_eq!(client.rx_calls.get(), 2);
+        assert_eq!(*client.rx_data.borrow(), b"ab");
+
+        // A prefix as long as the buffer is not stored in it.
+        assert!(uart
+            .receive_after_prefix(b"SYNC", static_buffer(4), 4)
+            .is_ok());
+        stream.push_rx(b"SYNCdata");
+        stream.service(uart);
+        assert_eq!(client.rx_calls.get(), 3);
+        assert_eq!(client.rx_len.get(), 4);
+        assert_eq!(*client.rx_data.borrow(), b"data");
+    }
+
+    #[test]
+    fn receive_until_delimiter_sequence() {
+        let uart = mock_uart();
+        let client = Client::attach(uart);
+        configure_115200(uart);
+
+        assert_eq!(
+            uart.receive_until_seq(buffer(16), 16, b"")
+                .map_err(|(e, _)| e),
+            Err(ErrorCode::INVAL)
+        );
+
+        // A broken partial match doesn't end the receive or lose bytes.
+        for keep in [true, false] {
+            uart.set_keep_delimiter(keep);
+            assert!(uart.receive_until_seq(buffer(16), 16, b"\r\n").is_ok());
+            for &byte in b"a\r\rb\r\n" {
+                assert_eq!(client.rx_calls.get(), if keep { 0 } else { 1 });
+                uart.receive_byte(byte);
+            }
+            assert_eq!(client.rx_rval.get(), Some(Ok(())));
+            assert_eq!(client.rx_len.get(), if keep { 6 } else { 4 });
+        }
+
+        // Without the delimiter the buffer fills up.
+        assert!(uart.receive_until_seq(buffer(4), 4, b"\r\n").is_ok());
+        for &byte in b"abc\r" {
+            uart.receive_byte(byte);
+        }
+        assert_eq!(client.rx_calls.get(), 3);
+        assert_eq!(client.rx_rval.get(), Some(Err(ErrorCode::SIZE)));
+        assert_eq!(client.rx_len.get(), 4);
+    }
+
+    #[test]
+    fn transmit_then_await_ack() {
+        let (uart, hw) = mock_uart_with_hw();
+        let client = Client::attach(uart);
+        let regs = uart.registers;
+        configure_115200(uart);
+
+        assert_eq!(
+            uart.transmit_then_await_ack(buffer(4), 4, 0x06, 1000)
+                .map_err(|(e, _)| e),
+            Err(ErrorCode::NOSUPPORT)
+        );
+        let time: &'static SteppingTime = Box::leak(Box::new(SteppingTime::default()));
+        uart.set_time_source(time);
+        regs.rxdata.write(rxdata::empty::SET);
+
+        // Other bytes are ignored until the ACK arrives.
+        assert!(uart
+            .transmit_then_await_ack(buffer(4), 4, 0x06, 1000)
+            .is_ok());
+        hw.set_pending(interrupt::txwm::SET.value);
+        uart.handle_interrupt();
+        assert_eq!(client.tx_calls.get(), 0);
+        assert_eq!(uart.interrupts_enabled(), (false, true));
+        assert_eq!(
+            uart.transmit_buffer(buffer(4), 4).map_err(|(e, _)| e),
+            Err(ErrorCode::BUSY)
+        );
+
+        regs.rxdata.write(rxdata::data.val(0x15));
+        hw.set_pending(interrupt::rxwm::SET.value);
+        uart.handle_interrupt();
+        assert_eq!(client.tx_calls.get(), 0);
+
+        regs.rxdata.write(rxdata::data.val(0x06));
+        uart.handle_interrupt();
+        assert_eq!(client.tx_calls.get(), 1);
+        assert_eq!(client.tx_rval.get(), Some(Ok(())));
+        assert_eq!(client.tx_len.get(), 4);
+        assert_eq!(uart.interrupts_enabled(), (false, true));
+
+        // Without an ACK the transmit fails at the timeout.
+        regs.rxdata.write(rxdata::empty::SET);
+        assert!(uart
+            .transmit_then_await_ack(buffer(4), 4, 0x06, 1000)
+            .is_ok());
+        hw.set_pending(interrupt::txwm::SET.value);
+        uart.handle_interrupt();
+        time.ticks.set(time.ticks.get() + 1_000_000);
+        uart.alarm();
+        assert_eq!(client.tx_calls.get(), 2);
+        assert_eq!(client.tx_rval.get(), Some(Err(ErrorCode::FAIL)));
+        assert_eq!(uart.interrupts_enabled(), (false, true));
+    }
+
+    #[test]
+    fn transmit_strobed() {
+        let (uart, hw) = mock_uart_with_hw();
+        let client = Client::attach(uart);
+        let regs = uart.registers;
+        configure_115200(uart);
+
+        // Four frames of 10 bits at 115200 baud, whether the bytes are still
+        // in the buffer or in the FIFO.
+        assert_eq!(uart.transmit_strobed(buffer(4), 4), Ok(()));
+        assert_eq!(uart.time_remaining_us(), Some(347));
+        uart.strobe();
+        assert_eq!(uart.time_remaining_us(), Some(347));
+        for _ in 0..3 {
+            uart.strobe();
+        }
+        assert_eq!(uart.time_remaining_us(), Some(347));
+        assert_eq!(client.tx_calls.get(), 0);
+
+        // Strobes past the end are ignored, the FIFO draining completes it.
+        uart.strobe();
+        hw.set_pending(interrupt::txwm::SET.value);
+        uart.handle_interrupt();
+        assert_eq!(client.tx_calls.get(), 1);
+        assert_eq!(client.tx_len.get(), 4);
+        assert_eq!(uart.time_remaining_us(), None);
+        assert!(!regs.txctrl.is_set(txctrl::txen));
+    }
+
+    #[test]
+    fn transmit_at_baud_restores_from_alarm() {
+        let (uart, hw) = mock_uart_with_hw();
+        let client = Client::attach(uart);
+        let regs = uart.registers;
+        configure_115200(uart);
+
+        assert_eq!(
+            uart.transmit_at_baud(buffer(4), 4, 1_000_000)
+                .map_err(|(e, _)| e),
+            Err(ErrorCode::NOSUPPORT)
+        );
+        assert_eq!(regs.div.read(div::div), 138);
+        let time: &'static SteppingTime = Box::leak(Box::new(SteppingTime::default()));
+        uart.set_time_source(time);
+
+        assert!(uart.transmit_at_baud(buffer(4), 4, 1_000_000).is_ok());
+        assert_eq!(regs.div.read(div::div), 15);
+
+        // Drained, but the last byte may still be shifting out.
+        hw.set_pending(interrupt::txwm::SET.value);
+        uart.handle_interrupt();
+        assert_eq!(client.tx_calls.get(), 0);
+        assert_eq!(regs.div.read(div::div), 15);
+        assert_eq!(
+            uart.transmit_buffer(buffer(4), 4).map_err(|(e, _)| e),
+            Err(ErrorCode::BUSY)
+        );
+
+        time.ticks.set(time.ticks.get() + 1_000_000);
+        uart.alarm();
+        assert_eq!(client.tx_calls.get(), 1);
+        assert_eq!(client.tx_rval.get(), Some(Ok(())));
+        assert_eq!(regs.div.read(div::div), 138);
+    }
+
+    #[cfg(feature = "diagnostics")]
+    #[test]
+    fn debug_state_line() {
+        let (uart, hw) = mock_uart_with_hw();
+        Client::attach(uart);
+        uart.set_name("uart0");
+        assert_eq!(
+            std::format!("{}", uart.debug_state()),
+            "uart0: tx idle rx idle baud 0 nstop 1 ie 0x0 ip 0x0"
+        );
+
+        configure_115200(uart);
+        // The mock FIFO never fills, so all of the transmit is written.
+        assert!(uart.transmit_buffer(buffer(64), 64).is_ok());
+        assert!(uart.receive_buffer(buffer(4), 4).is_ok());
+        hw.set_pending(interrupt::txwm::SET.value);
+        assert_eq!(
+            std::format!("{}", uart.debug_state()),
+            "uart0: tx 64/64 rx 0/4 baud 115200 nstop 1 ie 0x3 ip 0x1"
+        );
+    }
+
+    #[cfg(feature = "diagnostics")]
+    #[test]
+    fn completion_counters() {
+        let (uart, stream) = HostStream::new_uart(16_000_000);
+        let client = Client::attach(uart);
+        configure_115200(uart);
+
+        assert!(uart.transmit_buffer(static_buffer(4), 4).is_ok());
+        assert!(uart.receive_buffer(static_buffer(2), 2).is_ok());
+        stream.push_rx(b"ab");
+        stream.service(uart);
+        assert_eq!((client.tx_calls.get(), client.rx_calls.get()), (1, 1));
+        assert_eq!(uart.completed_transmits(), 1);
+        assert_eq!(uart.completed_receives(), 1);
+
+        // Transfers which fail are not counted.
+        assert!(uart.transmit_buffer(static_buffer(4), 4).is_ok());
+        assert!(uart.receive_buffer(static_buffer(2), 2).is_ok());
+        let _ = uart.recover();
+        assert_eq!((client.tx_calls.get(), client.rx_calls.get()), (2, 2));
+        assert_eq!(client.tx_rval.get(), Some(Err(ErrorCode::FAIL)));
+        assert_eq!(client.rx_rval.get(), Some(Err(ErrorCode::FAIL)));
+        assert_eq!(uart.completed_transmits(), 1);
+        assert_eq!(uart.completed_receives(), 1);
+
+        // Deferred callbacks count when they are delivered.
+        let (_, handle) = attach_deferred_call(uart);
+        uart.set_deferred_callbacks(true);
+        assert!(uart.transmit_buffer(static_buffer(4), 4).is_ok());
+        assert!(uart.receive_buffer(static_buffer(2), 2).is_ok());
+        stream.push_rx(b"cd");
+        stream.service(uart);
+        assert_eq!(uart.completed_transmits(), 1);
+        assert_eq!(uart.completed_receives(), 1);
+        uart.call(handle);
+        assert_eq!((client.tx_calls.get(), client.rx_calls.get()), (3, 3));
+        assert_eq!(uart.completed_transmits(), 2);
+        assert_eq!(uart.completed_receives(), 2);
+
+        // Each counter resets on its own.
+        uart.reset_completed_transmits();
+        assert_eq!(uart.completed_transmits(), 0);
+        assert_eq!(uart.completed_receives(), 2);
+        uart.reset_completed_receives();
+        assert_eq!(uart.completed_receives(), 0);
+    }
+
+    /// Hands out the chunks it was created with, and records the
+    /// callbacks of the stream.
+    struct Chunks {
+        chunks: core::cell::RefCell<std::vec::Vec<&'static mut [u8]>>,
+        returned: Cell<usize>,
+        complete: Cell<Option<(usize, Result<(), ErrorCode>)>>,
+    }
+
+    impl super::ChunkSource for Chunks {
+        fn next_chunk(&self) -> Option<&'static mut [u8]> {
+            let mut chunks = self.chunks.borrow_mut();
+            if chunks.is_empty() {
+                None
+            } else {
+                Some(chunks.remove(0))
+            }
+        }
+
+        fn chunk_transmitted(&self, _chunk: &'static mut [u8]) {
+            self.returned.set(self.returned.get() + 1);
+        }
+
+        fn stream_complete(&self, tx_len: usize, rval: Result<(), ErrorCode>) {
+            self.complete.set(Some((tx_len, rval)));
+        }
+    }
+
+    #[test]
+    fn transmit_stream_defers_empty_chunks() {
+        let (uart, stream) = HostStream::new_uart(16_000_000);
+        configure_115200(uart);
+        let chunk = |bytes: &[u8]| -> &'static mut [u8] { Box::leak(bytes.into()) };
+        let source: &'static Chunks = Box::leak(Box::new(Chunks {
+            chunks: core::cell::RefCell::new(vec![chunk(b""), chunk(b"ab"), chunk(b"")]),
+            returned: Cell::new(0),
+            complete: Cell::new(None),
+        }));
+
+        // The empty first chunk is not handed back from within the call.
+        assert_eq!(uart.transmit_stream(source), Ok(()));
+        assert_eq!(source.returned.get(), 0);
+
+        stream.service(uart);
+        assert_eq!(source.returned.get(), 3);
+        assert_eq!(source.complete.get(), Some((2, Ok(()))));
+        assert_eq!(stream.drain_tx(), b"ab");
+
+        assert_eq!(uart.transmit_stream(source), Err(ErrorCode::SIZE));
+    }
+
+    #[cfg(feature = "diagnostics")]
+    #[test]
+    fn config_history_names_the_uart() {
+        let uart = mock_uart();
+        uart.set_name("uart0");
+        configure_115200(uart);
+
+        let names: std::vec::Vec<_> = uart
+            .config_history()
+            .map(|event| std::format!("{}", event.name))
+            .collect();
+        assert_eq!(names, ["uart0", "uart0"]);
+    }
+
+    #[test]
+    fn host_stream() {
+        let (uart, stream) = HostStream::new_uart(16_000_000);
+        let client = Client::attach(uart);
+        configure_115200(uart);
+
+        assert!(uart.transmit_buffer(buffer(5), 3).is_ok());
+        stream.service(uart);
+        assert_eq!(client.tx_calls.get(), 1);
+        assert_eq!(stream.drain_tx(), [0, 0, 0]);
+
+        // Bytes beyond the posted receive can stay queued for the next one.
+        uart.set_no_buffer_policy(RxNoBufferPolicy::Overrun)
+            .unwrap();
+        assert!(uart.receive_buffer(static_buffer(4), 4).is_ok());
+        stream.push_rx(b"hello");
+        stream.service(uart);
+        assert_eq!(client.rx_calls.get(), 1);
+        assert_eq!(client.rx_len.get(), 4);
+        assert!(uart.receive_buffer(static_buffer(4), 1).is_ok());
+        stream.service(uart);
+        assert_eq!(client.rx_calls.get(), 2);
+        assert_eq!(client.rx_len.get(), 1);
+        assert_eq!(uart.interrupts_enabled(), (false, false));
+    }
+
+    #[test]
+    fn timing_capture_hands_back_buffers() {
+        let (uart, stream) = HostStream::new_uart(16_000_000);
+        Client::attach(uart);
+        configure_115200(uart);
+        let capture =
+            |len| -> &'static mut [(u32, u8)] { Box::leak(vec![(0, 0); len].into_boxed_slice()) };
+
+        assert!(uart.start_timing_capture(capture(4)).is_none());
+        assert!(uart.receive_buffer(static_buffer(2), 2).is_ok());
+        stream.push_rx(b"ab");
+        stream.service(uart);
+        assert_eq!(uart.stop_timing_capture(), 2);
+
+        // Restarting before the stopped capture was taken returns it...
+        let stopped = uart.start_timing_capture(capture(3)).unwrap();
+        assert_eq!((stopped.len(), stopped[0].1, stopped[1].1), (4, b'a', b'b'));
+        // ...and so does restarting a running one.
+        assert_eq!(
+            uart.start_timing_capture(stopped).map(|buf| buf.len()),
+            Some(3)
+        );
+
+        assert_eq!(uart.stop_timing_capture(), 0);
+        assert_eq!(uart.take_timing_capture().map(|buf| buf.len()), Some(4));
+        assert!(uart.take_timing_capture().is_none());
+    }
+
+    #[test]
+    fn rx_interbyte_gap() {
+        let (uart, stream) = HostStream::new_uart(16_000_000);
+        let client = Client::attach(uart);
+        let time: &'static SteppingTime = Box::leak(Box::default());
+        uart.set_time_source(time);
+        configure_115200(uart);
+        uart.set_rx_gap_measurement(true);
+
+        assert!(uart.receive_buffer(static_buffer(4), 2).is_ok());
+        stream.push_rx(b"a");
+        stream.service(uart);
+        assert_eq!(uart.last_rx_interbyte_gap_us(), None);
+
+        time.ticks.set(time.ticks.get() + 500);
+        stream.push_rx(b"b");
+        stream.service(uart);
+        assert_eq!(client.rx_calls.get(), 1);
+        let gap = uart.last_rx_interbyte_gap_us().unwrap();
+        assert!((500..600).contains(&gap), "gap {}", gap);
+
+        uart.set_rx_gap_measurement(false);
+        assert_eq!(uart.last_rx_interbyte_gap_us(), None);
+    }
+
+    /// Records the last `transaction_done`.
+    #[derive(Default)]
+    struct TransactionRecorder {
+        calls: Cell<usize>,
+        rx_len: Cell<usize>,
+        rval: Cell<Option<Result<(), ErrorCode>>>,
+        error: Cell<Option<uart::Error>>,
+    }
+
+    impl super::TransactionClient for TransactionRecorder {
+        fn transaction_done(
+            &self,
+            _tx_buffer: &'static mut [u8],
+            _rx_buffer: &'static mut [u8],
+            rx_len: usize,
+            rval: Result<(), ErrorCode>,
+            error: uart::Error,
+        ) {
+            self.calls.set(self.calls.get() + 1);
+            self.rx_len.set(rx_len);
+            self.rval.set(Some(rval));
+            self.error.set(Some(error));
+        }
+    }
+
+    #[test]
+    fn transmit_then_receive_timeout_needs_time_source() {
+        let (uart, stream) = HostStream::new_uart(16_000_000);
+        let txn: &'static TransactionRecorder = Box::leak(Box::default());
+        uart.set_transaction_client(txn);
+        configure_115200(uart);
+
+        // The timeout can't be honored, so the exchange isn't started.
+        assert_eq!(
+            uart.transmit_then_receive(static_buffer(2), 2, static_buffer(4), 4, Some(1000))
+                .map_err(|(e, _, _)| e),
+            Err(ErrorCode::NOSUPPORT)
+        );
+        assert!(stream.drain_tx().is_empty());
+
+        // Without a timeout it doesn't need one.
+        assert!(uart
+            .transmit_then_receive(static_buffer(2), 2, static_buffer(4), 4, None)
+            .is_ok());
+        stream.service(uart);
+        stream.push_rx(b"abcd");
+        stream.service(uart);
+        assert_eq!(txn.calls.get(), 1);
+        assert_eq!(txn.rx_len.get(), 4);
+        assert_eq!(txn.rval.get(), Some(Ok(())));
+    }
+
+    #[test]
+    fn truncated_receives() {
+        let (uart, stream) = HostStream::new_uart(16_000_000);
+        let client = Client::attach(uart);
+        let time: &'static SteppingTime = Box::leak(Box::default());
+        uart.set_time_source(time);
+        configure_115200(uart);
+        uart.set_reject_truncated(true);
+
+        // Aborting before the frame has started is a plain abort.
+        assert!(uart.receive_buffer(static_buffer(4), 4).is_ok());
+        assert_eq!(uart.receive_abort(), Err(ErrorCode::BUSY));
+        assert_eq!(client.rx_rval.get(), Some(Err(ErrorCode::CANCEL)));
+        assert_eq!(client.rx_error.get(), Some(uart::Error::Aborted));
+
+        // Aborting mid-frame still cancels, but reports the truncation.
+        assert!(uart.receive_buffer(static_buffer(4), 4).is_ok());
+        stream.push_rx(b"a");
+        stream.service(uart);
+        assert_eq!(uart.receive_abort(), Err(ErrorCode::BUSY));
+        assert_eq!(client.rx_calls.get(), 2);
+        assert_eq!(client.rx_len.get(), 1);
+        assert_eq!(client.rx_rval.get(), Some(Err(ErrorCode::CANCEL)));
+        assert_eq!(client.rx_error.get(), Some(uart::Error::Truncated));
+
+        // So does the timeout of an exchange whose response stops
+        // mid-frame.
+        let txn: &'static TransactionRecorder = Box::leak(Box::default());
+        uart.set_transaction_client(txn);
+        assert!(uart
+            .transmit_then_receive(static_buffer(2), 2, static_buffer(4), 4, Some(1000))
+            .is_ok());
+        stream.service(uart);
+        stream.push_rx(b"ab");
+        stream.service(uart);
+        assert_eq!(txn.calls.get(), 0);
+        time.ticks.set(time.ticks.get() + 1_000_000);
+        uart.alarm();
+        assert_eq!(txn.calls.get(), 1);
+        assert_eq!(txn.rx_len.get(), 2);
+        assert_eq!(txn.rval.get(), Some(Err(ErrorCode::CANCEL)));
+        assert_eq!(txn.error.get(), Some(uart::Error::Truncated));
+    }
+
+    #[test]
+    fn no_buffer_policies() {
+        let (uart, stream) = HostStream::new_uart(16_000_000);
+        let client = Client::attach(uart);
+        configure_115200(uart);
+
+        // Dropped: the next receive only sees new data.
+        assert!(uart.receive_buffer(static_buffer(2), 2).is_ok());
+        stream.push_rx(b"abcd");
+        stream.service(uart);
+        assert_eq!(client.rx_calls.get(), 1);
+        assert!(uart.receive_buffer(static_buffer(2), 2).is_ok());
+        stream.service(uart);
+        assert_eq!(client.rx_calls.get(), 1);
+        stream.push_rx(b"ef");
+        stream.service(uart);
+        assert_eq!(client.rx_calls.get(), 2);
+
+        // Kept in the ring the board provides, and delivered from the
+        // deferred call. What doesn't fit in the ring is dropped.
+        assert_eq!(
+            uart.set_no_buffer_policy(RxNoBufferPolicy::BufferInRing),
+            Err(ErrorCode::OFF)
+        );
+        let (ddc, handle) = attach_deferred_call(uart);
+        assert_eq!(
+            uart.set_no_buffer_policy(RxNoBufferPolicy::BufferInRing),
+            Err(ErrorCode::OFF)
+        );
+        uart.set_rx_ring(static_buffer(3));
+        uart.set_no_buffer_policy(RxNoBufferPolicy::BufferInRing)
+            .unwrap();
+        stream.push_rx(b"ghij");
+        stream.service(uart);
+        assert!(uart.receive_buffer(static_buffer(2), 2).is_ok());
+        assert_eq!(client.rx_calls.get(), 2);
+        assert!(ddc.has_pending());
+        uart.call(handle);
+        assert_eq!(client.rx_calls.get(), 3);
+        assert_eq!(client.rx_error.get(), Some(uart::Error::None));
+        assert_eq!(*client.rx_data.borrow(), b"gh");
+
+        // The rest of the ring wraps around for the next bytes.
+        stream.push_rx(b"kl");
+        stream.service(uart);
+        assert!(uart.receive_buffer(static_buffer(3), 3).is_ok());
+        uart.call(handle);
+        assert_eq!(client.rx_calls.get(), 4);
+        assert_eq!(*client.rx_data.borrow(), b"ikl");
+
+        // Left in the FIFO: the hardware one isn't emulated by the stream,
+        // so check the overrun report on the MMIO mock.
+        let (uart, hw) = mock_uart_with_hw();
+        let client = Client::attach(uart);
+        configure_115200(uart);
+        uart.set_no_buffer_policy(RxNoBufferPolicy::Overrun)
+            .unwrap();
+        assert!(uart.receive_buffer(buffer(2), 2).is_ok());
+        uart.registers.rxdata.write(rxdata::data.val(0x11));
+        hw.set_pending(interrupt::rxwm::SET.value);
+        uart.handle_interrupt();
+        assert_eq!(client.rx_error.get(), Some(uart::Error::None));
+        assert_eq!(uart.interrupts_enabled(), (false, false));
+        assert!(uart.receive_buffer(buffer(2), 2).is_ok());
+        uart.handle_interrupt();
+        assert_eq!(client.rx_calls.get(), 2);
+        assert_eq!(client.rx_error.get(), Some(uart::Error::OverrunError));
+    }
+
+    #[test]
+    fn receive_rtu_frames() {
+        let (uart, stream) = HostStream::new_uart(16_000_000);
+        let client = Client::attach(uart);
+        configure_115200(uart);
+
+        assert_eq!(
+            uart.receive_rtu_frames(static_buffer(8), 8)
+                .map_err(|(e, _)| e),
+            Err(ErrorCode::NOSUPPORT)
+        );
+        let time: &'static SteppingTime = Box::leak(Box::default());
+        uart.set_time_source(time);
+
+        // A pause shorter than the 1750 us gap doesn't end the frame, the
+        // gap after it does.
+        assert!(uart.receive_rtu_frames(static_buffer(8), 8).is_ok());
+        stream.push_rx(&[0x01, 0x03, 0x00]);
+        stream.service(uart);
+        time.ticks.set(time.ticks.get() + 1000);
+        uart.alarm();
+        stream.push_rx(&[0x10]);
+        stream.service(uart);
+        time.ticks.set(time.ticks.get() + 1000);
+        uart.alarm();
+        assert_eq!(client.rx_calls.get(), 0);
+        time.ticks.set(time.ticks.get() + 1000);
+        uart.alarm();
+        assert_eq!(client.rx_calls.get(), 1);
+        assert_eq!(client.rx_rval.get(), Some(Ok(())));
+        assert_eq!(*client.rx_data.borrow(), [0x01, 0x03, 0x00, 0x10]);
+
+        // A single byte is a frame of its own.
+        assert!(uart.receive_rtu_frames(static_buffer(8), 8).is_ok());
+        stream.push_rx(&[0x07]);
+        stream.service(uart);
+        time.ticks.set(time.ticks.get() + 2000);
+        uart.alarm();
+        assert_eq!(client.rx_calls.get(), 2);
+        assert_eq!(client.rx_rval.get(), Some(Ok(())));
+        assert_eq!(*client.rx_data.borrow(), [0x07]);
+
+        // A frame longer than the buffer is cut off, and only reported at
+        // the gap.
+        assert!(uart.receive_rtu_frames(static_buffer(4), 4).is_ok());
+        stream.push_rx(&[1, 2, 3, 4, 5, 6]);
+        stream.service(uart);
+        assert_eq!(client.rx_calls.get(), 2);
+        time.ticks.set(time.ticks.get() + 2000);
+        uart.alarm();
+        assert_eq!(client.rx_calls.get(), 3);
+        assert_eq!(client.rx_rval.get(), Some(Err(ErrorCode::SIZE)));
+        assert_eq!(*client.rx_data.borrow(), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn rx_retry_after_rtu_overflow() {
+        let (uart, hw) = mock_uart_with_hw();
+        let client = Client::attach(uart);
+        let regs = uart.registers;
+        let time: &'static SteppingTime = Box::leak(Box::new(SteppingTime::default()));
+        uart.set_time_source(time);
+        configure_115200(uart);
+        uart.set_rx_retry(2);
+
+        assert!(uart.receive_rtu_frames(buffer(4), 4).is_ok());
+
+        // Every RTU frame is longer than the buffer: a full FIFO arrives,
+        // then the line goes quiet for the inter-frame gap.
+        let overflow = || {
+            regs.rxdata.write(rxdata::data.val(0x11));
+            hw.set_pending(interrupt::rxwm::SET.value);
+            uart.handle_interrupt();
+            regs.rxdata.write(rxdata::empty::SET);
+            time.ticks.set(time.ticks.get() + 1_000_000);
+            uart.alarm();
+        };
+
+        // The first failures are retried with the same buffer.
+        overflow();
+        overflow();
+        assert_eq!(client.rx_calls.get(), 0);
+        assert_eq!(uart.interrupts_enabled(), (false, true));
+
+        // Then the client hears about it.
+        overflow();
+        assert_eq!(client.rx_calls.get(), 1);
+        assert_eq!(client.rx_rval.get(), Some(Err(ErrorCode::SIZE)));
+        assert_eq!(client.rx_len.get(), 4);
+        assert_eq!(uart.interrupts_enabled(), (false, true));
+    }
+
+    #[test]
+    fn rx_retry_after_overrun() {
+        let (uart, hw) = mock_uart_with_hw();
+        let client = Client::attach(uart);
+        let regs = uart.registers;
+        configure_115200(uart);
+        uart.set_no_buffer_policy(RxNoBufferPolicy::Overrun)
+            .unwrap();
+        uart.set_rx_retry(2);
+
+        // The RX FIFO is full whenever the receive is (re-)posted.
+        regs.rxctrl.write(rxctrl::enable::SET);
+        regs.rxdata.write(rxdata::data.val(0x11));
+        hw.set_pending(interrupt::rxwm::SET.value);
+        let capture: &'static mut [(u32, u8)] = Box::leak(vec![(0, 0); 16].into_boxed_slice());
+        uart.start_timing_capture(capture);
+        assert!(uart.receive_buffer(buffer(2), 2).is_ok());
+
+        // Each completion is retried until the retries are used up, and
+        // only then reported: three attempts of two bytes each.
+        uart.handle_interrupt();
+        assert_eq!(uart.stop_timing_capture(), 6);
+        assert_eq!(client.rx_calls.get(), 1);
+        assert_eq!(client.rx_rval.get(), Some(Ok(())));
+        assert_eq!(client.rx_error.get(), Some(uart::Error::OverrunError));
+        assert_eq!(client.rx_len.get(), 2);
+
+        // A fresh receive gets a fresh count, and is not reported as overrun
+        // once the FIFO is no longer full.
+        uart.set_rx_retry(1);
+        hw.set_pending(0);
+        assert!(uart.receive_buffer(buffer(2), 2).is_ok());
+        hw.set_pending(interrupt::rxwm::SET.value);
+        uart.handle_interrupt();
+        assert_eq!(client.rx_calls.get(), 2);
+        assert_eq!(client.rx_error.get(), Some(uart::Error::None));
+    }
+
+    #[test]
+    fn clear_pending_interrupts() {
+        let (uart, hw) = mock_uart_with_hw();
+        let client = Client::attach(uart);
+        let regs = uart.registers;
+
+        // State left over from before the reset: the transmitter is on with
+        // an empty FIFO, and RX data is waiting.
+        regs.txctrl.write(txctrl::txen::SET + txctrl::txcnt.val(1));
+        regs.rxdata.write(rxdata::data.val(0x42));
+        hw.set_pending((interrupt::txwm::SET + interrupt::rxwm::SET).value);
+
+        assert_eq!(uart.clear_pending_interrupts(), Ok(()));
+
+        assert_eq!(regs.txctrl.read(txctrl::txcnt), 0);
+        assert!(regs.txctrl.is_set(txctrl::txen));
+        assert_eq!(regs.ie.get(), 0);
+
+        // Nothing is delivered by a stray interrupt.
+        uart.handle_interrupt();
+        assert_eq!(client.tx_calls.get(), 0);
+        assert_eq!(client.rx_calls.get(), 0);
+
+        // A TX FIFO that never empties makes it give up rather than hang,
+        // after still resetting the watermark.
+        regs.txctrl.write(txctrl::txen::SET + txctrl::txcnt.val(1));
+        hw.set_pending(0);
+        assert_eq!(uart.clear_pending_interrupts(), Err(ErrorCode::FAIL));
+        assert_eq!(regs.txctrl.read(txctrl::txcnt), 0);
+    }
+
+    #[test]
+    fn validate_config_detects_corruption() {
+        let uart = mock_uart();
+        let regs = uart.registers;
+        configure_115200(uart);
+        assert_eq!(uart.validate_config(), Ok(()));
+
+        assert!(uart.receive_buffer(buffer(4), 4).is_ok());
+        assert_eq!(uart.transmit_buffer(buffer(4), 4), Ok(()));
+        assert_eq!(uart.validate_config(), Ok(()));
+
+        // A glitch flips a bit of the divisor.
+        regs.div.write(div::div.val(138 ^ 0x100));
+        assert_eq!(uart.validate_config(), Err(ErrorCode::FAIL));
+        regs.div.write(div::div.val(138));
+        assert_eq!(uart.validate_config(), Ok(()));
+
+        // The receiver gets switched off under a posted receive.
+        regs.rxctrl.write(rxctrl::enable::CLEAR);
+        assert_eq!(uart.validate_config(), Err(ErrorCode::FAIL));
+        regs.rxctrl
+            .write(rxctrl::enable::SET + rxctrl::counter.val(0));
+
+        // The stop bits change under a transmit in progress.
+        regs.txctrl.modify(txctrl::nstop::TwoStopBits);
+        assert_eq!(uart.validate_config(), Err(ErrorCode::FAIL));
     }
 }
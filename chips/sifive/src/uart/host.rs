@@ -0,0 +1,219 @@
+//! In-memory byte stream backend of the UART for tests on the host.
+//!
+//! With the `std` feature (and in this crate's own tests) a `Uart` can be
+//! created on top of a `HostStream` instead of the MMIO registers. Bytes the
+//! driver writes to the TX FIFO are collected in a `VecDeque`, and bytes
+//! pushed with `HostStream::push_rx` are what the driver reads from the RX
+//! FIFO, so capsules built on the UART HIL can be driven through the real
+//! `Transmit` and `Receive` implementations:
+//!
+//! ```rust,ignore
+//! let (uart, stream) = HostStream::new_uart(16_000_000);
+//! uart.configure(params)?;
+//! uart.set_receive_client(capsule);
+//! uart.receive_buffer(static_buffer(4), 4)?;
+//! stream.push_rx(b"ping");
+//! stream.service(uart);
+//! assert_eq!(stream.drain_tx(), b"pong");
+//! ```
+//!
+//! The TX FIFO drains instantly and the RX FIFO is unbounded. Nothing
+//! happens in the background: interrupts are only raised by `service`,
+//! which also runs the driver's interrupt handling, once the test has
+//! queued data or started a transfer. Deferred calls and alarms are not
+//! run by the stream and have to be driven by the test like on the MMIO
+//! mock. The blocking `_sync` helpers are not supported.
+//!
+//! The HIL needs `'static` buffers and clients. Tests get those by leaking
+//! heap allocations, which `new_uart` and `static_buffer` do; the leak is
+//! bounded by the test and reclaimed when the test process exits.
+
+extern crate std;
+
+use core::cell::{Cell, RefCell};
+use std::boxed::Box;
+use std::collections::VecDeque;
+use std::vec;
+use std::vec::Vec;
+
+use super::{interrupt, rxctrl, txctrl, Uart, UartRegisters};
+use kernel::utilities::registers::interfaces::Readable;
+use kernel::utilities::StaticRef;
+
+/// Upper bound on the interrupts raised by one `HostStream::service`, so a
+/// driver which keeps an interrupt asserted fails the test instead of
+/// hanging it.
+const MAX_SERVICE_PASSES: usize = 1024;
+
+/// Zeroed memory standing in for the MMIO registers of a UART, leaked to
+/// make it `'static`.
+///
+/// `ip` is `ReadOnly`, so it must not be changed through the shared
+/// references the driver reads the registers with. The simulated hardware
+/// writes it through the raw pointer the memory was allocated with instead.
+#[derive(Copy, Clone)]
+pub(super) struct MockRegisters(*mut UartRegisters);
+
+impl MockRegisters {
+    pub(super) fn new() -> MockRegisters {
+        MockRegisters(Box::into_raw(Box::new(unsafe { core::mem::zeroed() })))
+    }
+
+    /// The registers, to create a `Uart` on top of or to look at them.
+    pub(super) fn base(self) -> StaticRef<UartRegisters> {
+        unsafe { StaticRef::new(self.0) }
+    }
+
+    /// Report the given interrupt conditions in `ip`.
+    pub(super) fn set_pending(self, pending: u32) {
+        // `ReadOnly` is `repr(transparent)`, and no reference is created on
+        // the way to the field.
+        unsafe {
+            core::ptr::write_volatile(core::ptr::addr_of_mut!((*self.0).ip) as *mut u32, pending)
+        };
+    }
+}
+
+/// The lines of a UART on the host, see the module documentation.
+pub struct HostStream {
+    hw: MockRegisters,
+    tx: RefCell<VecDeque<u8>>,
+    rx: RefCell<VecDeque<u8>>,
+    #[cfg(test)]
+    div_writes_to_drop: Cell<usize>,
+    tx_fifo_depth: Cell<Option<usize>>,
+    tx_fifo_held: Cell<usize>,
+}
+
+impl HostStream {
+    /// Create a UART running from a clock of `clock_frequency` Hz, backed
+    /// by a new stream. Both are leaked to make them `'static`.
+    pub fn new_uart(clock_frequency: u32) -> (&'static Uart<'static>, &'static HostStream) {
+        // The registers are still there for the configuration, only the
+        // FIFOs and the interrupt state are taken over by the stream.
+        let hw = MockRegisters::new();
+        let stream: &'static HostStream = Box::leak(Box::new(HostStream {
+            hw,
+            tx: RefCell::new(VecDeque::new()),
+            rx: RefCell::new(VecDeque::new()),
+            #[cfg(test)]
+            div_writes_to_drop: Cell::new(0),
+            tx_fifo_depth: Cell::new(None),
+            tx_fifo_held: Cell::new(0),
+        }));
+        let uart: &'static Uart<'static> =
+            Box::leak(Box::new(Uart::new(hw.base(), clock_frequency)));
+        uart.host.set(stream);
+        (uart, stream)
+    }
+
+    /// Queue `bytes` to be received by the UART.
+    pub fn push_rx(&self, bytes: &[u8]) {
+        self.rx.borrow_mut().extend(bytes);
+    }
+
+    /// Return and forget the bytes transmitted by the UART so far.
+    pub fn drain_tx(&self) -> Vec<u8> {
+        self.tx.borrow_mut().drain(..).collect()
+    }
+
+    /// Raise the UART interrupts the current state calls for and service
+    /// them, until no enabled interrupt is pending anymore.
+    ///
+    /// `txwm` is pending while the watermark is above 0, as the TX FIFO is
+    /// always empty, and `rxwm` while more bytes are queued than the RX
+    /// watermark. Panics if the interrupt is still asserted after
+    /// `MAX_SERVICE_PASSES` rounds.
+    pub fn service(&self, uart: &Uart) {
+        let regs = self.hw.base();
+        for _ in 0..MAX_SERVICE_PASSES {
+            let mut pending = 0;
+            if regs.txctrl.read(txctrl::txcnt) > 0 {
+                pending |= interrupt::txwm::SET.value;
+            }
+            if self.rx.borrow().len() > regs.rxctrl.read(rxctrl::counter) as usize {
+                pending |= interrupt::rxwm::SET.value;
+            }
+            self.hw.set_pending(pending);
+
+            if pending & regs.ie.get() == 0 {
+                return;
+            }
+            uart.handle_interrupt();
+        }
+        panic!(
+            "UART interrupt still asserted after {} passes",
+            MAX_SERVICE_PASSES
+        );
+    }
+
+    /// Lose the next `count` writes of the driver to the divisor register,
+    /// like a bus fabric which drops MMIO writes during stalls. See
+    /// `Uart::set_write_verify`. Only for this crate's own tests.
+    #[cfg(test)]
+    pub(super) fn drop_div_writes(&self, count: usize) {
+        self.div_writes_to_drop.set(count);
+    }
+
+    /// Give the TX FIFO a depth of `depth` entries for
+    /// `Uart::detect_fifo_depth` to find. While the transmitter is
+    /// disabled, bytes written by the driver then count as waiting in the
+    /// FIFO, and it reports full once `depth` of them are. Enabling the
+    /// transmitter empties it again. The bytes show up in `drain_tx` right
+    /// away like all others. Only for this crate's own tests.
+    #[cfg(test)]
+    pub(super) fn set_tx_fifo_depth(&self, depth: usize) {
+        self.tx_fifo_depth.set(Some(depth));
+        self.tx_fifo_held.set(0);
+    }
+
+    /// The bytes waiting in the TX FIFO, see `set_tx_fifo_depth`.
+    fn tx_fifo_level(&self) -> usize {
+        if self.hw.base().txctrl.is_set(txctrl::txen) {
+            self.tx_fifo_held.set(0);
+        }
+        self.tx_fifo_held.get()
+    }
+
+    /// Whether the TX FIFO is full, see `set_tx_fifo_depth`.
+    pub(super) fn tx_fifo_full(&self) -> bool {
+        let level = self.tx_fifo_level();
+        self.tx_fifo_depth
+            .get()
+            .map_or(false, |depth| level >= depth)
+    }
+
+    /// Whether the TX FIFO is empty, see `set_tx_fifo_depth`.
+    pub(super) fn tx_fifo_empty(&self) -> bool {
+        self.tx_fifo_level() == 0
+    }
+
+    /// Whether the divisor write the driver is about to do is lost.
+    #[cfg(test)]
+    pub(super) fn drop_div_write(&self) -> bool {
+        let count = self.div_writes_to_drop.get();
+        self.div_writes_to_drop.set(count.saturating_sub(1));
+        count > 0
+    }
+
+    /// A byte written to the TX FIFO by the driver.
+    pub(super) fn push_tx(&self, byte: u8) {
+        if self.hw.base().txctrl.is_set(txctrl::txen) {
+            self.tx_fifo_held.set(0);
+        } else {
+            self.tx_fifo_held.set(self.tx_fifo_held.get() + 1);
+        }
+        self.tx.borrow_mut().push_back(byte);
+    }
+
+    /// The next byte for the driver to read from the RX FIFO.
+    pub(super) fn pop_rx(&self) -> Option<u8> {
+        self.rx.borrow_mut().pop_front()
+    }
+}
+
+/// Allocate a zeroed `'static` buffer of `len` bytes for the HIL calls, by
+/// leaking it.
+pub fn static_buffer(len: usize) -> &'static mut [u8] {
+    Box::leak(vec![0; len].into_boxed_slice())
+}
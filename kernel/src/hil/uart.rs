@@ -56,6 +56,10 @@ pub enum Error {
 
     /// Read or write was aborted early
     Aborted,
+
+    /// A framed receive ended before the frame reached its declared length
+    /// or terminator
+    Truncated,
 }
 
 pub trait Uart<'a>: Configure + Transmit<'a> + Receive<'a> {}